@@ -1,5 +1,9 @@
 npm run gen_can_test_interface
 
+# the test identities are generated on the fly, so let anyone upload
+dfx canister call storage set_default_role '(opt variant { Uploader })'
+
 # tape tests/file_scaling_manager.test.cjs
 
+tape tests/file_storage.test.cjs
 tape tests/storage.test.cjs
\ No newline at end of file
@@ -0,0 +1,148 @@
+use candid::{candid_method, Principal};
+use ic_cdk_macros::{query, update};
+
+use crate::{
+    memory::STATE,
+    types::{Role, State, StorageError},
+};
+
+/// Role `principal` acts with: controllers of the canister are always `Controller`,
+/// everyone else gets the role granted to them, or the configured default.
+pub(crate) fn role_of(principal: &Principal) -> Option<Role> {
+    if ic_cdk::api::is_controller(principal) {
+        return Some(Role::Controller);
+    }
+    STATE.with(|state| granted_role(&state.borrow(), principal))
+}
+
+/// A granted role applies even when it is below the default, so that granting
+/// `Reader` takes uploads away from someone under an `Uploader` default.
+fn granted_role(state: &State, principal: &Principal) -> Option<Role> {
+    state
+        .roles
+        .get(principal)
+        .map(|role| *role)
+        .or(state.config.default_role)
+}
+
+fn has_role(granted: Option<Role>, role: Role) -> bool {
+    granted.is_some_and(|granted| granted >= role)
+}
+
+fn require(role: Role) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if has_role(role_of(&caller), role) {
+        Ok(())
+    } else {
+        Err(format!("{caller} does not have the {role:?} role"))
+    }
+}
+
+pub fn is_admin() -> Result<(), String> {
+    require(Role::Admin)
+}
+
+/// Anonymous callers cannot upload whatever their role: they would all own, and so
+/// share, every asset any of them uploads.
+pub fn is_uploader() -> Result<(), String> {
+    if ic_cdk::caller() == Principal::anonymous() {
+        return Err("anonymous callers cannot upload".to_string());
+    }
+    require(Role::Uploader)
+}
+
+pub fn is_reader() -> Result<(), String> {
+    require(Role::Reader)
+}
+
+/// Admins can hand out roles below their own; only controllers can make admins.
+fn ensure_can_manage(role: Role) -> Result<(), StorageError> {
+    match role_of(&ic_cdk::caller()) {
+        Some(Role::Controller) => Ok(()),
+        Some(granted) if granted > role => Ok(()),
+        _ => Err(StorageError::NotAuthorized),
+    }
+}
+
+#[update(guard = "is_admin")]
+#[candid_method(update)]
+pub fn grant_role(principal: Principal, role: Role) -> Result<(), StorageError> {
+    ensure_can_manage(role)?;
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if let Some(current) = state.roles.get(&principal).map(|role| *role) {
+            ensure_can_manage(current)?;
+        }
+        state
+            .roles
+            .insert(principal, role)
+            .map_err(|_| StorageError::OutOfMemory)?;
+        Ok(())
+    })
+}
+
+/// Puts `principal` back on the default role.
+#[update(guard = "is_admin")]
+#[candid_method(update)]
+pub fn revoke_role(principal: Principal) -> Result<(), StorageError> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if let Some(current) = state.roles.get(&principal).map(|role| *role) {
+            ensure_can_manage(current)?;
+        }
+        state.roles.remove(&principal);
+        Ok(())
+    })
+}
+
+/// Role of principals that were not granted one; `None` locks out everyone else.
+#[update(guard = "is_admin")]
+#[candid_method(update)]
+pub fn set_default_role(role: Option<Role>) -> Result<(), StorageError> {
+    if let Some(role) = role {
+        ensure_can_manage(role)?;
+    }
+    STATE.with(|state| state.borrow_mut().config.default_role = role);
+    Ok(())
+}
+
+#[query(guard = "is_admin")]
+#[candid_method(query)]
+pub fn get_role(principal: Principal) -> Option<Role> {
+    role_of(&principal)
+}
+
+#[query]
+#[candid_method(query)]
+pub fn my_role() -> Option<Role> {
+    role_of(&ic_cdk::caller())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Config;
+    use ic_stable_memory::stable_memory_init;
+
+    #[test]
+    fn grants_roles_below_the_default() {
+        stable_memory_init();
+        let mut state = State::default();
+        state.config.default_role = Some(Role::Uploader);
+        let reader = Principal::from_slice(&[1]);
+        state.roles.insert(reader, Role::Reader).unwrap();
+
+        let granted = granted_role(&state, &reader);
+        assert_eq!(granted, Some(Role::Reader));
+        assert!(has_role(granted, Role::Reader));
+        assert!(!has_role(granted, Role::Uploader));
+
+        let other = Principal::from_slice(&[2]);
+        assert!(has_role(granted_role(&state, &other), Role::Uploader));
+        state.config.default_role = Config::default().default_role;
+        assert!(!has_role(granted_role(&state, &other), Role::Uploader));
+        assert!(has_role(granted_role(&state, &other), Role::Reader));
+        state.config.default_role = None;
+        assert!(!has_role(granted_role(&state, &other), Role::Reader));
+    }
+}
@@ -0,0 +1,92 @@
+use candid::candid_method;
+use ic_cdk_macros::{query, update};
+
+use crate::{
+    access_control::{is_reader, is_uploader},
+    memory::STATE,
+    router::normalize_alias,
+    types::{StableString, State, StorageError},
+};
+
+/// Serves the asset `asset_id` under `path` too. An alias of another asset of the
+/// caller is moved over; returns the normalized path.
+#[update(guard = "is_uploader")]
+#[candid_method(update)]
+pub fn set_alias(path: String, asset_id: u128) -> Result<String, StorageError> {
+    let caller = ic_cdk::caller();
+    let path = normalize_alias(&path).ok_or(StorageError::InvalidAlias)?;
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        match state.assets.get(&asset_id) {
+            None => return Err(StorageError::AssetNotFound),
+            Some(asset) if !asset.is_owner(&caller) => return Err(StorageError::NotOwner),
+            Some(_) => {}
+        }
+        let current = state.aliases.get(&path).map(|id| *id);
+        if let Some(current) = current {
+            if state.assets.get(&current).map(|asset| asset.owner) != Some(caller) {
+                return Err(StorageError::AliasTaken);
+            }
+        }
+        let key = StableString::new(path.clone()).map_err(|_| StorageError::OutOfMemory)?;
+        state
+            .aliases
+            .insert(key, asset_id)
+            .map_err(|_| StorageError::OutOfMemory)?;
+        Ok(path)
+    })
+}
+
+#[update(guard = "is_uploader")]
+#[candid_method(update)]
+pub fn remove_alias(path: String) -> Result<(), StorageError> {
+    let caller = ic_cdk::caller();
+    let path = normalize_alias(&path).ok_or(StorageError::InvalidAlias)?;
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let Some(asset_id) = state.aliases.get(&path).map(|id| *id) else {
+            return Err(StorageError::AliasNotFound);
+        };
+        if state.assets.get(&asset_id).map(|asset| asset.owner) != Some(caller) {
+            return Err(StorageError::NotOwner);
+        }
+        state.aliases.remove(&path);
+        Ok(())
+    })
+}
+
+/// Aliases of the asset, sorted.
+#[query(guard = "is_reader")]
+#[candid_method(query)]
+pub fn list_aliases(asset_id: u128) -> Result<Vec<String>, StorageError> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let state = state.borrow();
+        match state.assets.get(&asset_id) {
+            None => return Err(StorageError::AssetNotFound),
+            Some(asset) if !asset.can_read(&caller) => return Err(StorageError::NotAuthorized),
+            Some(_) => {}
+        }
+        let mut aliases: Vec<String> = state
+            .aliases
+            .iter()
+            .filter(|(_, id)| **id == asset_id)
+            .map(|(path, _)| String::clone(&path))
+            .collect();
+        aliases.sort();
+        Ok(aliases)
+    })
+}
+
+/// Removes the aliases of a deleted asset.
+pub(crate) fn remove_aliases_of(state: &mut State, asset_id: u128) {
+    let paths: Vec<String> = state
+        .aliases
+        .iter()
+        .filter(|(_, id)| **id == asset_id)
+        .map(|(path, _)| String::clone(&path))
+        .collect();
+    for path in paths {
+        state.aliases.remove(&path);
+    }
+}
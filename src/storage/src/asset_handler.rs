@@ -1,145 +1,939 @@
-use std::collections::HashMap;
-
-use candid::{candid_method, CandidType};
+use candid::{candid_method, CandidType, Principal};
 use ic_cdk_macros::{query, update};
 use ic_stable_memory::collections::SHashMap;
 // use ic_stable_structures::BoundedStorable;
 
 use crate::{
+    access_control::{is_reader, is_uploader, role_of},
+    aliases::remove_aliases_of,
+    certification::{certify_asset, uncertify_asset},
+    compression::{has_valid_header, is_compressible, queue_compression, queue_decoding},
     memory::STATE,
-    types::{AssetQuery, ContentEncoding, StableAsset, StableString},
-    utils::generate_url,
+    quota::{charge, check_asset, check_bytes, release},
+    types::{
+        name_key, AssetQuery, ContentEncoding, Disposition, Permission, Role, StableAsset,
+        StableEncoding, StableString, StableUpload, State, StorageError, Visibility,
+    },
+    upload_handler::hash_chunks,
+    urls::asset_url,
+    utils::{sanitize_file_name, to_hex},
 };
 
-const MODULO_VALUE: u32 = 400_000_000;
+#[derive(CandidType, serde::Deserialize)]
+pub struct CommitUploadArg {
+    pub upload_id: u128,
+    /// SHA-256 of the whole file.
+    pub sha256: Vec<u8>,
+    /// Defaults to `Public`.
+    pub visibility: Option<Visibility>,
+    pub description: Option<String>,
+    /// `Cache-Control` to serve the asset with, for instance
+    /// `public, max-age=31536000, immutable`.
+    pub cache_control: Option<String>,
+    /// Whether to also store gzip and deflate encodings, generated in the background,
+    /// of text-like content uploaded as identity. Each is only kept if it is smaller.
+    /// Defaults to `false`.
+    pub compress: Option<bool>,
+    /// Whether to also store the identity content, decoded in the background, of
+    /// content uploaded in another encoding, for clients accepting none of the stored
+    /// encodings. It counts against the owner's quota; without it such clients are
+    /// served content decoded on the fly, when small enough. Defaults to `false`.
+    pub decode: Option<bool>,
+    /// Defaults to `Attachment`.
+    pub disposition: Option<Disposition>,
+}
+
+/// Rejects values that are empty or could not be sent as a header value.
+fn check_cache_control(value: &str) -> Result<(), StorageError> {
+    if value.trim().is_empty() || value.chars().any(|c| c.is_ascii_control()) {
+        return Err(StorageError::InvalidCacheControl);
+    }
+    Ok(())
+}
+
+/// An upload session whose chunks were checked to form the file that was announced.
+struct CompleteUpload {
+    /// Chunk ids in index order.
+    chunk_ids: Vec<u128>,
+    total_length: u64,
+    sha256: [u8; 32],
+}
+
+/// Bytes of content looked at to check that it is a stream in its declared encoding.
+const HEADER_PREFIX_LENGTH: usize = 64 * 1024;
+/// Most missing chunk indices one `MissingChunks` error lists.
+const MAX_REPORTED_MISSING_CHUNKS: usize = 1000;
+
+/// Checks, without changing anything, that the upload session of `caller` has all of
+/// its chunks, that they add up to the announced size and to `sha256`, and that they
+/// start like a stream in the declared encoding. The chunks have to be hashed first,
+/// see `hash_chunks`.
+fn check_upload(
+    state: &State,
+    caller: &Principal,
+    upload_id: u128,
+    sha256: &[u8],
+) -> Result<CompleteUpload, StorageError> {
+    let chunk_ids: Vec<(u32, u128)> = match state.uploads.get(&upload_id) {
+        None => return Err(StorageError::UploadNotFound),
+        Some(upload) if upload.owner != *caller => return Err(StorageError::NotOwner),
+        Some(upload) => {
+            let mut ids: Vec<(u32, u128)> = upload
+                .chunks
+                .iter()
+                .map(|(index, id)| (*index, *id))
+                .collect();
+            ids.sort_by_key(|(index, _)| *index);
+            ids
+        }
+    };
+
+    let Some(&(last_index, _)) = chunk_ids.last() else {
+        return Err(StorageError::NoChunks);
+    };
+    let chunks_not_found: Vec<u128> = chunk_ids
+        .iter()
+        .filter(|(_, id)| !state.chunks.contains_key(id))
+        .map(|(_, id)| *id)
+        .collect();
+    if !chunks_not_found.is_empty() {
+        return Err(StorageError::ChunksNotFound(chunks_not_found));
+    }
+    let upload = state.uploads.get(&upload_id).unwrap();
+    let mut missing: Vec<u32> = (0..last_index)
+        .filter(|index| chunk_ids.binary_search_by_key(index, |(i, _)| *i).is_err())
+        .take(MAX_REPORTED_MISSING_CHUNKS)
+        .collect();
+    let received: u64 = chunk_ids
+        .iter()
+        .map(|(_, id)| state.chunks.get(id).unwrap().content.len() as u64)
+        .sum();
+    // chunk sizes are up to the client, so without gaps, falling short of the announced
+    // size only tells that the chunk after the highest uploaded one is missing
+    if missing.is_empty() && received < upload.total_size {
+        missing.push(last_index + 1);
+    }
+    if !missing.is_empty() {
+        return Err(StorageError::MissingChunks(missing));
+    }
+
+    if upload.hashed_chunks as usize != chunk_ids.len() {
+        return Err(StorageError::HashPending {
+            hashed: upload.hasher.length(),
+        });
+    }
+    if upload.total_size != received {
+        return Err(StorageError::SizeMismatch {
+            expected: upload.total_size,
+            actual: received,
+        });
+    }
+    let actual = upload.hasher.finalize();
+    if sha256 != actual {
+        return Err(StorageError::HashMismatch {
+            expected: to_hex(sha256),
+            actual: to_hex(&actual),
+        });
+    }
+    let mut prefix = vec![];
+    for (_, id) in &chunk_ids {
+        let missing = HEADER_PREFIX_LENGTH.saturating_sub(prefix.len());
+        if missing == 0 {
+            break;
+        }
+        let chunk = state.chunks.get(id).unwrap();
+        prefix.extend(chunk.content.iter().take(missing).map(|b| *b));
+    }
+    if !has_valid_header(upload.content_encoding, &prefix) {
+        return Err(StorageError::InvalidEncoding(upload.content_encoding));
+    }
+    Ok(CompleteUpload {
+        chunk_ids: chunk_ids.into_iter().map(|(_, id)| id).collect(),
+        total_length: received,
+        sha256: actual,
+    })
+}
+
+/// Removes a checked upload session, moving its chunks into the returned encoding.
+///
+/// Chunks are moved out of `state` one by one, so running out of memory traps to roll
+/// the whole call back.
+fn take_upload(
+    state: &mut State,
+    upload_id: u128,
+    complete: CompleteUpload,
+) -> (StableUpload, StableEncoding) {
+    let mut content = SHashMap::new_with_capacity(complete.chunk_ids.len())
+        .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    let upload = state.uploads.remove(&upload_id).unwrap();
+    complete
+        .chunk_ids
+        .iter()
+        .enumerate()
+        .for_each(|(index, id)| {
+            let chunk = state.chunks.remove(id).unwrap();
+            content
+                .insert(index as u32, chunk.content)
+                .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+        });
+    let encoding = StableEncoding {
+        content,
+        chunk_count: complete.chunk_ids.len() as u32,
+        total_length: complete.total_length,
+        sha256: complete.sha256,
+    };
+    (upload, encoding)
+}
+
+/// Turns a finished upload session into an asset, moving its chunks in index order.
+///
+/// The content is stored in the encoding the session was created with; further
+/// encodings can be added with `commit_encoding`, or generated in the background, see
+/// `compress` and `decode`.
+#[update(guard = "is_uploader")]
+#[candid_method(update)]
+pub fn commit_upload(args: CommitUploadArg) -> Result<u128, StorageError> {
+    let caller = ic_cdk::caller();
+    if let Some(cache_control) = &args.cache_control {
+        check_cache_control(cache_control)?;
+    }
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        hash_chunks(&mut state, args.upload_id);
+        let complete = check_upload(&state, &caller, args.upload_id, &args.sha256)?;
+        check_asset(&state, &caller)?;
+
+        let (upload, content) = take_upload(&mut state, args.upload_id, complete);
+        let mut encodings = SHashMap::new();
+        encodings
+            .insert(upload.content_encoding, content)
+            .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+        let description = args.description.map(|description| {
+            StableString::new(description).unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"))
+        });
+        let cache_control = args.cache_control.map(|cache_control| {
+            StableString::new(cache_control)
+                .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"))
+        });
+        let compress = args.compress == Some(true)
+            && upload.content_encoding == ContentEncoding::Identity
+            && is_compressible(&upload.content_type);
+        let decode =
+            args.decode == Some(true) && upload.content_encoding != ContentEncoding::Identity;
+        let file_name = sanitize_file_name(&upload.file_name);
+        let now = ic_cdk::api::time();
+        let id = state.get_asset_id();
+        let asset = StableAsset {
+            encodings,
+            file_name: StableString::new(file_name.clone())
+                .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory")),
+            owner: caller,
+            id,
+            content_type: upload.content_type,
+            visibility: args.visibility.unwrap_or(Visibility::Public),
+            acl: SHashMap::new(),
+            url_key_version: 0,
+            created_at: now,
+            updated_at: now,
+            description,
+            cache_control,
+            disposition: args.disposition.unwrap_or(Disposition::Attachment),
+        };
+        certify_asset(&asset);
+        state
+            .assets
+            .insert(id, asset)
+            .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+        // ids only grow, so the new asset is the latest under its name
+        let name = StableString::new(name_key(&caller, &file_name))
+            .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+        state
+            .names
+            .insert(name, id)
+            .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+        charge(&mut state, &caller, 0, 1);
+        if compress {
+            queue_compression(&mut state, id);
+        }
+        if decode {
+            queue_decoding(&mut state, id);
+        }
+        Ok(id)
+    })
+}
 
 #[derive(CandidType, serde::Deserialize)]
-pub struct AssetArg {
-    pub checksum: u32,
-    pub chunk_ids: Vec<u128>,
-    pub content_type: String,
-    pub file_name: String,
-    pub content_encoding: ContentEncoding,
+pub struct CommitEncodingArg {
+    pub asset_id: u128,
+    pub upload_id: u128,
+    /// SHA-256 of the whole encoded file.
+    pub sha256: Vec<u8>,
 }
 
-#[update]
+/// Adds the content of a finished upload session to an existing asset, in the encoding
+/// the session was created with. An encoding stored before is replaced.
+///
+/// The uploaded file must be the asset's content in that encoding; this is not checked.
+#[update(guard = "is_uploader")]
 #[candid_method(update)]
-pub fn commit_batch(args: AssetArg) -> u128 {
+pub fn commit_encoding(args: CommitEncodingArg) -> Result<(), StorageError> {
     let caller = ic_cdk::caller();
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        let mut chunks_to_commit = vec![];
-        let mut chunks_not_found = vec![];
-        let mut chunks_not_owned = vec![];
+        let owner = match state.assets.get(&args.asset_id) {
+            None => return Err(StorageError::AssetNotFound),
+            Some(asset) if !asset.can_write(&caller) => return Err(StorageError::NotAuthorized),
+            Some(asset) => asset.owner,
+        };
+        hash_chunks(&mut state, args.upload_id);
+        let complete = check_upload(&state, &caller, args.upload_id, &args.sha256)?;
+        if owner != caller {
+            // the bytes move over to the owner below, in place of a replaced encoding
+            let encoding = state.uploads.get(&args.upload_id).unwrap().content_encoding;
+            let replaced = state
+                .assets
+                .get(&args.asset_id)
+                .and_then(|asset| asset.encodings.get(&encoding).map(|v| v.total_length))
+                .unwrap_or(0);
+            check_bytes(
+                &state,
+                &owner,
+                complete.total_length.saturating_sub(replaced),
+            )?;
+        }
 
-        args.chunk_ids
-            .iter()
-            .for_each(|id| match state.chunks.get(id) {
-                None => chunks_not_found.push(id.clone()),
-                Some(chunk) if chunk.owner != caller => chunks_not_owned.push(id.clone()),
-                Some(chunk) => chunks_to_commit.push((id.clone(), chunk.order)),
-            });
+        let (upload, content) = take_upload(&mut state, args.upload_id, complete);
+        let size = content.total_length;
+        let mut asset = state.assets.get_mut(&args.asset_id).unwrap();
+        uncertify_asset(&asset);
+        let replaced = asset
+            .encodings
+            .insert(upload.content_encoding, content)
+            .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+        asset.updated_at = ic_cdk::api::time();
+        certify_asset(&asset);
+        drop(asset);
 
-        if chunks_to_commit.len() == 0 {
-            ic_cdk::trap("No chunks found")
+        // the stored bytes are accounted to the owner of the asset they belong to
+        if owner != caller {
+            release(&mut state, &caller, size, 0);
+            charge(&mut state, &owner, size, 0);
         }
-        if chunks_not_found.len() > 0 {
-            let error_msg = format!("Chunks not found: {:?}", chunks_not_found);
-            ic_cdk::trap(&error_msg)
+        if let Some(replaced) = replaced {
+            release(&mut state, &owner, replaced.total_length, 0);
         }
-        if chunks_not_owned.len() > 0 {
-            let error_msg = format!("Chunks not owned: {:?}", chunks_not_owned);
-            ic_cdk::trap(&error_msg)
+        Ok(())
+    })
+}
+
+/// Drops one stored encoding of an asset; the last one cannot be removed.
+#[update(guard = "is_uploader")]
+#[candid_method(update)]
+pub fn remove_encoding(asset_id: u128, encoding: ContentEncoding) -> Result<(), StorageError> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let Some(mut asset) = state.assets.get_mut(&asset_id) else {
+            return Err(StorageError::AssetNotFound);
+        };
+        if !asset.can_write(&caller) {
+            return Err(StorageError::NotAuthorized);
+        }
+        if !asset.encodings.contains_key(&encoding) {
+            return Err(StorageError::EncodingNotFound);
+        }
+        if asset.encodings.len() == 1 {
+            return Err(StorageError::LastEncoding);
         }
+        uncertify_asset(&asset);
+        let removed = asset.encodings.remove(&encoding).unwrap();
+        asset.updated_at = ic_cdk::api::time();
+        certify_asset(&asset);
+        let owner = asset.owner;
+        drop(asset);
+        release(&mut state, &owner, removed.total_length, 0);
+        Ok(())
+    })
+}
 
-        let mut checksum: u32 = 0;
-        let mut content =
-            SHashMap::new_with_capacity(chunks_to_commit.len()).expect("Failed to allocate memory");
-        let mut chunk_size = 0;
+/// Whether `principal` may delete the asset or change who can read it, which sharing
+/// it with `Write` does not allow: only its owner and admins can.
+fn can_manage(asset: &StableAsset, principal: &Principal) -> bool {
+    asset.is_owner(principal) || role_of(principal).is_some_and(|role| role >= Role::Admin)
+}
 
-        chunks_to_commit.sort_by_key(|chunks| chunks.1);
+#[update(guard = "is_uploader")]
+#[candid_method(update)]
+pub fn delete_asset(id: u128) -> Result<(), StorageError> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        match state.assets.get(&id) {
+            None => return Err(StorageError::AssetNotFound),
+            Some(asset) if !can_manage(&asset, &caller) => return Err(StorageError::NotOwner),
+            Some(_) => {}
+        }
+        if let Some(asset) = state.assets.remove(&id) {
+            uncertify_asset(&asset);
+            release(&mut state, &asset.owner, asset.stored_bytes(), 1);
+            remove_aliases_of(&mut state, id);
+            unindex_name(&mut state, &asset);
+        }
+        Ok(())
+    })
+}
 
-        chunks_to_commit.iter().for_each(|(id, _)| {
-            let chunk = state.chunks.remove(id).unwrap();
+/// Points the name of a deleted asset at the latest asset of its owner left under it.
+fn unindex_name(state: &mut State, asset: &StableAsset) {
+    let key = name_key(&asset.owner, &asset.file_name);
+    if state.names.get(&key).map(|id| *id) != Some(asset.id) {
+        return;
+    }
+    let latest = state
+        .assets
+        .iter()
+        .filter(|(_, other)| other.owner == asset.owner && *other.file_name == *asset.file_name)
+        .map(|(id, _)| *id)
+        .max();
+    match latest {
+        Some(id) => {
+            let key =
+                StableString::new(key).unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+            state
+                .names
+                .insert(key, id)
+                .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+        }
+        None => {
+            state.names.remove(&key);
+        }
+    }
+}
 
-            content
-                .insert(chunk.order, chunk.content)
-                .expect("failed to insert");
+#[query(guard = "is_reader")]
+#[candid_method(query)]
+pub fn get_asset(id: u128) -> Result<AssetQuery, StorageError> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let state = state.borrow();
+        match state.assets.get(&id) {
+            None => Err(StorageError::AssetNotFound),
+            Some(asset) if !asset.can_read(&caller) => Err(StorageError::NotAuthorized),
+            Some(asset) => Ok(AssetQuery::new(&asset, asset_url(&state, &asset))),
+        }
+    })
+}
 
-            checksum = (checksum + chunk.checksum) % MODULO_VALUE;
+/// Largest number of assets returned by one `list_assets` call.
+const MAX_PAGE_SIZE: u32 = 100;
+/// Largest number of ids one `list_assets` call looks at, so that selective filters
+/// cannot exhaust the instruction limit.
+const MAX_SCANNED_IDS: u128 = 10_000;
 
-            ic_cdk::print(chunk.order.to_string());
-            ic_cdk::print(checksum.to_string());
+/// Criteria an asset must all meet to be listed; unset fields match every asset.
+#[derive(CandidType, serde::Deserialize, Default)]
+pub struct AssetFilter {
+    pub owner: Option<Principal>,
+    pub content_type_prefix: Option<String>,
+    pub name_contains: Option<String>,
+    /// Inclusive lower bound of `created_at`.
+    pub created_after: Option<u64>,
+    /// Exclusive upper bound of `created_at`.
+    pub created_before: Option<u64>,
+}
 
-            chunk_size += 1;
-        });
+impl AssetFilter {
+    fn matches(&self, asset: &StableAsset) -> bool {
+        self.owner.is_none_or(|owner| asset.owner == owner)
+            && self
+                .content_type_prefix
+                .as_ref()
+                .is_none_or(|prefix| asset.content_type.starts_with(prefix.as_str()))
+            && self
+                .name_contains
+                .as_ref()
+                .is_none_or(|name| asset.file_name.contains(name.as_str()))
+            && self
+                .created_after
+                .is_none_or(|after| asset.created_at >= after)
+            && self
+                .created_before
+                .is_none_or(|before| asset.created_at < before)
+    }
+}
 
-        if args.checksum != checksum {
-            let error_msg = format!("Checksum mismatch: {} != {}", args.checksum, checksum);
-            ic_cdk::trap(&error_msg);
+#[derive(CandidType)]
+pub struct AssetPage {
+    pub assets: Vec<AssetQuery>,
+    /// Cursor of the next page, `None` once every asset was looked at. A page can hold
+    /// fewer than `limit` assets and still have a next one.
+    pub next_cursor: Option<u128>,
+}
+
+/// Lists the assets the caller can read in id order, starting after `cursor`.
+#[query(guard = "is_reader")]
+#[candid_method(query)]
+pub fn list_assets(filter: AssetFilter, cursor: Option<u128>, limit: u32) -> AssetPage {
+    let caller = ic_cdk::caller();
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    STATE.with(|state| {
+        let state = state.borrow();
+        // ids are handed out in increasing order, so walking them needs no extra index
+        let start = cursor.map_or(1, |cursor| cursor.saturating_add(1));
+        let end = state.asset_count.min(start.saturating_add(MAX_SCANNED_IDS));
+        let mut assets = vec![];
+        for id in start..end {
+            if assets.len() == limit {
+                return AssetPage {
+                    assets,
+                    next_cursor: Some(id - 1),
+                };
+            }
+            if let Some(asset) = state.assets.get(&id) {
+                if asset.can_read(&caller) && filter.matches(&asset) {
+                    assets.push(AssetQuery::new(&asset, asset_url(&state, &asset)));
+                }
+            }
         }
-        // if content.len() as u32 > <Asset as BoundedStorable>::MAX_SIZE {
-        //     ic_cdk::trap("Exceeds allow file limit size")
-        // }
-        let id = state.get_asset_id();
-        let url = generate_url(id);
-        let asset = StableAsset {
-            content,
-            content_encoding: args.content_encoding,
-            file_name: StableString::new(args.file_name).unwrap(),
-            owner: caller,
-            chunk_size,
-            url,
-            id,
-            content_type: StableString::new(args.content_type).unwrap(),
+        AssetPage {
+            assets,
+            next_cursor: (end < state.asset_count).then_some(end - 1),
+        }
+    })
+}
+
+#[derive(CandidType, serde::Deserialize)]
+pub struct DownloadChunkArg {
+    pub asset_id: u128,
+    pub index: u32,
+    /// Defaults to identity, or to the only stored encoding if identity is not stored.
+    pub encoding: Option<ContentEncoding>,
+}
+
+#[derive(CandidType)]
+pub struct AssetChunk {
+    pub content: Vec<u8>,
+    pub index: u32,
+    pub chunk_count: u32,
+    pub encoding: ContentEncoding,
+}
+
+/// Authenticated download, for assets that are not public and so cannot be fetched
+/// over HTTP.
+#[query(guard = "is_reader")]
+#[candid_method(query)]
+pub fn download_chunk(args: DownloadChunkArg) -> Result<AssetChunk, StorageError> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let state = state.borrow();
+        let asset = match state.assets.get(&args.asset_id) {
+            None => return Err(StorageError::AssetNotFound),
+            Some(asset) if !asset.can_read(&caller) => return Err(StorageError::NotAuthorized),
+            Some(asset) => asset,
+        };
+        let encoding = args.encoding.unwrap_or_else(|| asset.default_encoding());
+        let Some(variant) = asset.encodings.get(&encoding) else {
+            return Err(StorageError::EncodingNotFound);
         };
-        state.assets.insert(id, asset).expect("failed to insert");
-        id
+        let Some(chunk) = variant.content.get(&args.index) else {
+            return Err(StorageError::ChunkNotFound);
+        };
+        Ok(AssetChunk {
+            content: chunk.iter().map(|b| *b).collect(),
+            index: args.index,
+            chunk_count: variant.chunk_count,
+            encoding,
+        })
     })
 }
 
-#[update]
+#[update(guard = "is_uploader")]
 #[candid_method(update)]
-pub fn delete_asset(id: u128) -> bool {
+pub fn set_visibility(id: u128, visibility: Visibility) -> Result<(), StorageError> {
     let caller = ic_cdk::caller();
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        match state.assets.get(&id) {
-            None => ic_cdk::trap("Asset not found"),
-            Some(asset) if asset.owner != caller => ic_cdk::trap("Asset not owned by caller"),
-            Some(_) => {
-                state.assets.remove(&id);
-                true
+        let Some(mut asset) = state.assets.get_mut(&id) else {
+            return Err(StorageError::AssetNotFound);
+        };
+        if !can_manage(&asset, &caller) {
+            return Err(StorageError::NotOwner);
+        }
+        uncertify_asset(&asset);
+        asset.visibility = visibility;
+        asset.updated_at = ic_cdk::api::time();
+        certify_asset(&asset);
+        Ok(())
+    })
+}
+
+/// Grants `principal` access to a shared asset, or takes it away with `None`.
+#[update(guard = "is_uploader")]
+#[candid_method(update)]
+pub fn set_permission(
+    id: u128,
+    principal: Principal,
+    permission: Option<Permission>,
+) -> Result<(), StorageError> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let Some(mut asset) = state.assets.get_mut(&id) else {
+            return Err(StorageError::AssetNotFound);
+        };
+        if !can_manage(&asset, &caller) {
+            return Err(StorageError::NotOwner);
+        }
+        match permission {
+            None => {
+                asset.acl.remove(&principal);
+            }
+            Some(permission) => {
+                asset
+                    .acl
+                    .insert(principal, permission)
+                    .map_err(|_| StorageError::OutOfMemory)?;
             }
         }
+        asset.updated_at = ic_cdk::api::time();
+        Ok(())
     })
 }
 
-#[query]
-#[candid_method(query)]
-pub fn get_asset(id: u128) -> AssetQuery {
+#[update(guard = "is_uploader")]
+#[candid_method(update)]
+pub fn set_description(id: u128, description: Option<String>) -> Result<(), StorageError> {
+    let caller = ic_cdk::caller();
     STATE.with(|state| {
-        let state = state.borrow();
-        match state.assets.get(&id) {
-            None => ic_cdk::trap("Asset not found"),
-            Some(asset) => AssetQuery::from(&*asset),
+        let mut state = state.borrow_mut();
+        let Some(mut asset) = state.assets.get_mut(&id) else {
+            return Err(StorageError::AssetNotFound);
+        };
+        if !asset.can_write(&caller) {
+            return Err(StorageError::NotAuthorized);
+        }
+        asset.description = description
+            .map(StableString::new)
+            .transpose()
+            .map_err(|_| StorageError::OutOfMemory)?;
+        asset.updated_at = ic_cdk::api::time();
+        Ok(())
+    })
+}
+
+/// Sets the `Cache-Control` the asset is served with, `None` restoring the default.
+#[update(guard = "is_uploader")]
+#[candid_method(update)]
+pub fn set_cache_control(id: u128, cache_control: Option<String>) -> Result<(), StorageError> {
+    let caller = ic_cdk::caller();
+    if let Some(cache_control) = &cache_control {
+        check_cache_control(cache_control)?;
+    }
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let Some(mut asset) = state.assets.get_mut(&id) else {
+            return Err(StorageError::AssetNotFound);
+        };
+        if !asset.can_write(&caller) {
+            return Err(StorageError::NotAuthorized);
+        }
+        asset.cache_control = cache_control
+            .map(StableString::new)
+            .transpose()
+            .map_err(|_| StorageError::OutOfMemory)?;
+        asset.updated_at = ic_cdk::api::time();
+        Ok(())
+    })
+}
+
+/// Sets whether the asset is displayed or downloaded by browsers, unless a request
+/// asks for a download.
+#[update(guard = "is_uploader")]
+#[candid_method(update)]
+pub fn set_disposition(id: u128, disposition: Disposition) -> Result<(), StorageError> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let Some(mut asset) = state.assets.get_mut(&id) else {
+            return Err(StorageError::AssetNotFound);
+        };
+        if !asset.can_write(&caller) {
+            return Err(StorageError::NotAuthorized);
         }
+        asset.disposition = disposition;
+        asset.updated_at = ic_cdk::api::time();
+        Ok(())
     })
 }
 
-#[query]
+#[query(guard = "is_uploader")]
 #[candid_method(query)]
-pub fn asset_list() -> HashMap<u128, AssetQuery> {
+pub fn get_permissions(id: u128) -> Result<Vec<(Principal, Permission)>, StorageError> {
+    let caller = ic_cdk::caller();
     STATE.with(|state| {
-        state
-            .borrow()
-            .assets
-            .iter()
-            .map(|(id, asset)| (*id, AssetQuery::from(&*asset)))
-            .collect()
+        let state = state.borrow();
+        match state.assets.get(&id) {
+            None => Err(StorageError::AssetNotFound),
+            Some(asset) if !can_manage(&asset, &caller) => Err(StorageError::NotOwner),
+            Some(asset) => Ok(asset
+                .acl
+                .iter()
+                .map(|(principal, permission)| (*principal, *permission))
+                .collect()),
+        }
     })
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{test_asset, StableChunk, StableSha256};
+    use ic_stable_memory::{collections::SVec, stable_memory_init};
+    use sha2::{Digest, Sha256};
+
+    /// Adds an identity upload session of `owner` with the chunks given by index.
+    fn test_upload(
+        state: &mut State,
+        owner: Principal,
+        chunks: &[(u32, &[u8])],
+        total_size: u64,
+    ) -> u128 {
+        let upload_id = state.get_upload_id();
+        let mut ids = SHashMap::new();
+        for (index, bytes) in chunks {
+            let id = state.get_chunk_id();
+            let mut content = SVec::new();
+            bytes.iter().for_each(|b| content.push(*b).unwrap());
+            let chunk = StableChunk {
+                content,
+                owner,
+                created_at: 0,
+                upload_id,
+                index: *index,
+                sha256: Sha256::digest(bytes).into(),
+                id,
+            };
+            state.chunks.insert(id, chunk).unwrap();
+            ids.insert(*index, id).unwrap();
+        }
+        let upload = StableUpload {
+            id: upload_id,
+            owner,
+            file_name: StableString::new("file.txt".to_string()).unwrap(),
+            content_type: StableString::new("text/plain".to_string()).unwrap(),
+            content_encoding: ContentEncoding::Identity,
+            total_size,
+            chunks: ids,
+            created_at: 0,
+            updated_at: 0,
+            hasher: StableSha256::default(),
+            hashed_chunks: 0,
+        };
+        state.uploads.insert(upload_id, upload).unwrap();
+        hash_chunks(state, upload_id);
+        upload_id
+    }
+
+    #[test]
+    fn reports_missing_chunks() {
+        stable_memory_init();
+        let mut state = State::default();
+        let owner = Principal::anonymous();
+        let sha256: [u8; 32] = Sha256::digest(b"abcdef").into();
+
+        let gap = test_upload(&mut state, owner, &[(0, b"ab"), (2, b"ef")], 6);
+        assert_eq!(
+            check_upload(&state, &owner, gap, &sha256).err(),
+            Some(StorageError::MissingChunks(vec![1]))
+        );
+        let last = test_upload(&mut state, owner, &[(0, b"ab"), (1, b"cd")], 6);
+        assert_eq!(
+            check_upload(&state, &owner, last, &sha256).err(),
+            Some(StorageError::MissingChunks(vec![2]))
+        );
+        let first = test_upload(&mut state, owner, &[(1, b"cd"), (2, b"ef")], 6);
+        assert_eq!(
+            check_upload(&state, &owner, first, &sha256).err(),
+            Some(StorageError::MissingChunks(vec![0]))
+        );
+        let far = test_upload(&mut state, owner, &[(5000, b"ab")], 6);
+        match check_upload(&state, &owner, far, &sha256) {
+            Err(StorageError::MissingChunks(missing)) => {
+                assert_eq!(missing.len(), MAX_REPORTED_MISSING_CHUNKS);
+                assert_eq!(missing[999], 999);
+            }
+            other => panic!("unexpected {:?}", other.err()),
+        }
+
+        let complete = test_upload(&mut state, owner, &[(0, b"ab"), (1, b"cd"), (2, b"ef")], 6);
+        let checked = check_upload(&state, &owner, complete, &sha256).unwrap();
+        assert_eq!(checked.total_length, 6);
+        assert_eq!(checked.chunk_ids.len(), 3);
+    }
+
+    #[test]
+    fn compares_the_running_hash() {
+        stable_memory_init();
+        let mut state = State::default();
+        let owner = Principal::anonymous();
+        let sha256: [u8; 32] = Sha256::digest(b"abcdef").into();
+
+        let id = test_upload(&mut state, owner, &[(0, b"ab"), (1, b"cd"), (2, b"ef")], 6);
+        let mut upload = state.uploads.get_mut(&id).unwrap();
+        upload.hasher = StableSha256::default();
+        upload.hashed_chunks = 0;
+        drop(upload);
+        assert_eq!(
+            check_upload(&state, &owner, id, &sha256).err(),
+            Some(StorageError::HashPending { hashed: 0 })
+        );
+        hash_chunks(&mut state, id);
+        assert!(check_upload(&state, &owner, id, &sha256).is_ok());
+        assert_eq!(
+            check_upload(&state, &owner, id, &[0; 32]).err(),
+            Some(StorageError::HashMismatch {
+                expected: to_hex(&[0; 32]),
+                actual: to_hex(&sha256),
+            })
+        );
+    }
+
+    #[test]
+    fn bounds_chunk_indices_by_size() {
+        stable_memory_init();
+        let mut state = State::default();
+        let owner = Principal::anonymous();
+        for (total_size, limit) in [(0, 1), (1, 1), (1024, 1), (1025, 2), (10 * 1024, 10)] {
+            let id = test_upload(&mut state, owner, &[], total_size);
+            let upload = state.uploads.get(&id).unwrap();
+            assert_eq!(upload.max_chunk_count(), limit);
+        }
+    }
+
+    #[test]
+    fn reindexes_names_of_deleted_assets() {
+        stable_memory_init();
+        let mut state = State::default();
+        for id in [1, 2, 3] {
+            state.assets.insert(id, test_asset(id, b"hello")).unwrap();
+        }
+        let key = name_key(&Principal::anonymous(), "file.txt");
+        let name = StableString::new(key.clone()).unwrap();
+        state.names.insert(name, 3).unwrap();
+        let latest = |state: &State| state.names.get(&key).map(|id| *id);
+
+        let asset = state.assets.remove(&2).unwrap();
+        unindex_name(&mut state, &asset);
+        assert_eq!(latest(&state), Some(3));
+
+        let asset = state.assets.remove(&3).unwrap();
+        unindex_name(&mut state, &asset);
+        assert_eq!(latest(&state), Some(1));
+
+        let asset = state.assets.remove(&1).unwrap();
+        unindex_name(&mut state, &asset);
+        assert_eq!(latest(&state), None);
+    }
+
+    #[test]
+    fn treats_anonymous_as_owning_nothing() {
+        stable_memory_init();
+        let mut asset = test_asset(1, b"hello");
+        asset.visibility = Visibility::Private;
+        let anonymous = Principal::anonymous();
+        assert!(!asset.can_read(&anonymous));
+        assert!(!asset.can_write(&anonymous));
+
+        let owner = Principal::from_slice(&[1]);
+        asset.owner = owner;
+        assert!(asset.can_write(&owner));
+        assert!(!asset.can_read(&anonymous));
+    }
+
+    #[test]
+    fn filters_assets() {
+        stable_memory_init();
+        let owner = Principal::from_slice(&[1]);
+        let mut asset = test_asset(1, b"hello");
+        asset.owner = owner;
+        asset.file_name = StableString::new("report 2024.pdf".to_string()).unwrap();
+        asset.content_type = StableString::new("application/pdf".to_string()).unwrap();
+        asset.created_at = 100;
+
+        assert!(AssetFilter::default().matches(&asset));
+        let filters = [
+            (
+                AssetFilter {
+                    owner: Some(owner),
+                    ..Default::default()
+                },
+                AssetFilter {
+                    owner: Some(Principal::anonymous()),
+                    ..Default::default()
+                },
+            ),
+            (
+                AssetFilter {
+                    content_type_prefix: Some("application/".to_string()),
+                    ..Default::default()
+                },
+                AssetFilter {
+                    content_type_prefix: Some("image/".to_string()),
+                    ..Default::default()
+                },
+            ),
+            (
+                AssetFilter {
+                    name_contains: Some("2024".to_string()),
+                    ..Default::default()
+                },
+                AssetFilter {
+                    name_contains: Some("2023".to_string()),
+                    ..Default::default()
+                },
+            ),
+            (
+                AssetFilter {
+                    created_after: Some(100),
+                    ..Default::default()
+                },
+                AssetFilter {
+                    created_after: Some(101),
+                    ..Default::default()
+                },
+            ),
+            (
+                AssetFilter {
+                    created_before: Some(101),
+                    ..Default::default()
+                },
+                AssetFilter {
+                    created_before: Some(100),
+                    ..Default::default()
+                },
+            ),
+        ];
+        for (matching, other) in &filters {
+            assert!(matching.matches(&asset));
+            assert!(!other.matches(&asset));
+        }
+
+        let combined = AssetFilter {
+            owner: Some(owner),
+            content_type_prefix: Some("application/".to_string()),
+            name_contains: Some("report".to_string()),
+            created_after: Some(50),
+            created_before: Some(150),
+        };
+        assert!(combined.matches(&asset));
+        let one_off = AssetFilter {
+            created_before: Some(100),
+            ..combined
+        };
+        assert!(!one_off.matches(&asset));
+    }
+}
+
 // #[update]
 // #[candid_method(update)]
 // pub fn insert_chunk(){
@@ -1,7 +1,6 @@
-use crate::{chunk_handler::*, types::*, asset_handler::*};
-use candid::export_service;
+use crate::{chunk_handler::*, types::*, asset_handler::*, upload_handler::*, quota::*};
+use candid::{export_service, Principal};
 use ic_cdk_macros::query;
-use std::collections::HashMap;
 
 #[query(name = "__get_candid_interface_tmp_hack")]
 fn export_candid() -> String {
@@ -0,0 +1,501 @@
+use std::cell::RefCell;
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use ic_http_certification::{
+    DefaultCelBuilder, DefaultFullCelExpression, DefaultResponseCertification,
+    DefaultResponseOnlyCelExpression, HttpCertification, HttpCertificationPath,
+    HttpCertificationTree, HttpCertificationTreeEntry, HttpRequest as CertifiedRequest,
+    HttpResponse as CertifiedResponse,
+};
+use ic_stable_memory::collections::SHashMap;
+use serde::Serialize;
+
+use crate::types::{ContentEncoding, HeaderField, StableAsset, StableEncoding, Visibility};
+
+const CERTIFICATE_HEADER: &str = "IC-Certificate";
+const CERTIFICATE_EXPRESSION_HEADER: &str = "IC-CertificateExpression";
+
+thread_local! {
+    /// Heap-only view of the certified responses; it is rebuilt from `STATE` whenever
+    /// the canister starts, so it never has to be persisted.
+    static HTTP_TREE: RefCell<HttpCertificationTree> = RefCell::default();
+}
+
+pub(crate) fn asset_path(asset_id: u128) -> String {
+    format!("/asset/{asset_id}")
+}
+
+/// Certifies the status code, body, `Content-Type` and `Content-Encoding` of full asset
+/// responses.
+fn asset_expression() -> DefaultResponseOnlyCelExpression<'static> {
+    DefaultCelBuilder::response_only_certification()
+        .with_response_certification(DefaultResponseCertification::certified_response_headers(
+            vec!["content-type", "content-encoding"],
+        ))
+        .build()
+}
+
+/// `Content-Type` and `Content-Encoding` of `asset` in `encoding`, along with the
+/// expression header every certified response carries.
+fn representation_headers(
+    asset: &StableAsset,
+    encoding: ContentEncoding,
+    expression: String,
+) -> Vec<(String, String)> {
+    let mut headers = vec![
+        ("content-type".to_string(), asset.content_type.clone()),
+        (CERTIFICATE_EXPRESSION_HEADER.to_string(), expression),
+    ];
+    // identity responses are sent without a `Content-Encoding` header
+    if encoding != ContentEncoding::Identity {
+        headers.push(("content-encoding".to_string(), encoding.token().to_string()));
+    }
+    headers
+}
+
+fn asset_certification(
+    asset: &StableAsset,
+    encoding: ContentEncoding,
+    variant: &StableEncoding,
+) -> HttpCertification {
+    let expression = asset_expression();
+    let response = CertifiedResponse {
+        status_code: 200,
+        headers: representation_headers(asset, encoding, expression.to_string()),
+        body: vec![],
+        upgrade: None,
+    };
+    HttpCertification::response_only(&expression, &response, Some(variant.sha256))
+        .expect("certification expression header is always present")
+}
+
+/// URLs that stand for every request to the path of `asset_id` in certifications of
+/// the request. No query parameter is certified, so the request hash only depends on
+/// whether the URL has a query string at all.
+fn request_urls(asset_id: u128) -> [String; 2] {
+    let path = asset_path(asset_id);
+    [format!("{path}?"), path]
+}
+
+/// Certifies the request method besides what `asset_expression` certifies, as the
+/// empty body of `HEAD` responses must not be accepted for a `GET`.
+fn head_expression() -> DefaultFullCelExpression<'static> {
+    DefaultCelBuilder::full_certification()
+        .with_response_certification(DefaultResponseCertification::certified_response_headers(
+            vec!["content-type", "content-encoding"],
+        ))
+        .build()
+}
+
+fn head_certification(
+    asset: &StableAsset,
+    encoding: ContentEncoding,
+    request_url: &str,
+) -> HttpCertification {
+    let expression = head_expression();
+    let request = CertifiedRequest {
+        method: "HEAD".to_string(),
+        url: request_url.to_string(),
+        headers: vec![],
+        body: vec![],
+    };
+    let response = CertifiedResponse {
+        status_code: 200,
+        headers: representation_headers(asset, encoding, expression.to_string()),
+        body: vec![],
+        upgrade: None,
+    };
+    HttpCertification::full(&expression, &request, &response, None)
+        .expect("certification expression header is always present")
+}
+
+/// Certifies the method and the empty `204` answering it, but no response header:
+/// `Allow` never changes, and the CORS headers depend on the request origin.
+fn options_expression() -> DefaultFullCelExpression<'static> {
+    DefaultCelBuilder::full_certification()
+        .with_response_certification(DefaultResponseCertification::certified_response_headers(
+            vec![],
+        ))
+        .build()
+}
+
+fn options_certification(request_url: &str) -> HttpCertification {
+    let expression = options_expression();
+    let request = CertifiedRequest {
+        method: "OPTIONS".to_string(),
+        url: request_url.to_string(),
+        headers: vec![],
+        body: vec![],
+    };
+    let response = CertifiedResponse {
+        status_code: 204,
+        headers: vec![(
+            CERTIFICATE_EXPRESSION_HEADER.to_string(),
+            expression.to_string(),
+        )],
+        body: vec![],
+        upgrade: None,
+    };
+    HttpCertification::full(&expression, &request, &response, None)
+        .expect("certification expression header is always present")
+}
+
+/// Certifies the `If-None-Match` request header along with the status code, empty body
+/// and `ETag` of `304 Not Modified` responses, so that a `304` is only accepted for a
+/// request naming the entity tag it answers with.
+fn not_modified_expression() -> DefaultFullCelExpression<'static> {
+    DefaultCelBuilder::full_certification()
+        .with_request_headers(vec!["if-none-match"])
+        .with_response_certification(DefaultResponseCertification::certified_response_headers(
+            vec!["etag"],
+        ))
+        .build()
+}
+
+fn not_modified_certification(variant: &StableEncoding, request_url: &str) -> HttpCertification {
+    let expression = not_modified_expression();
+    let request = CertifiedRequest {
+        method: "GET".to_string(),
+        url: request_url.to_string(),
+        headers: vec![("if-none-match".to_string(), variant.etag())],
+        body: vec![],
+    };
+    let response = CertifiedResponse {
+        status_code: 304,
+        headers: vec![
+            ("etag".to_string(), variant.etag()),
+            (
+                CERTIFICATE_EXPRESSION_HEADER.to_string(),
+                expression.to_string(),
+            ),
+        ],
+        body: vec![],
+        upgrade: None,
+    };
+    HttpCertification::full(&expression, &request, &response, None)
+        .expect("certification expression header is always present")
+}
+
+/// Body of the `406` sent on the certified path to clients accepting no stored encoding.
+pub(crate) const NOT_ACCEPTABLE_BODY: &[u8] = b"Not Acceptable";
+
+/// Certifies the status code and body of `406 Not Acceptable` responses; the certified
+/// path cannot serve content decoded on the fly.
+fn not_acceptable_expression() -> DefaultResponseOnlyCelExpression<'static> {
+    DefaultCelBuilder::response_only_certification()
+        .with_response_certification(DefaultResponseCertification::certified_response_headers(
+            vec![],
+        ))
+        .build()
+}
+
+fn not_acceptable_certification() -> HttpCertification {
+    let expression = not_acceptable_expression();
+    let response = CertifiedResponse {
+        status_code: 406,
+        headers: vec![(
+            CERTIFICATE_EXPRESSION_HEADER.to_string(),
+            expression.to_string(),
+        )],
+        body: NOT_ACCEPTABLE_BODY.to_vec(),
+        upgrade: None,
+    };
+    HttpCertification::response_only(&expression, &response, None)
+        .expect("certification expression header is always present")
+}
+
+/// Tree entries of the responses served for a public asset: for every encoding, the
+/// full content, the `304` answering a `GET` with its entity tag in `If-None-Match` and
+/// the `HEAD` response, plus the `OPTIONS` response and the `406` for clients accepting
+/// none of the encodings.
+fn asset_entries(asset: &StableAsset) -> Vec<HttpCertificationTreeEntry<'static>> {
+    let path = HttpCertificationPath::exact(asset_path(asset.id));
+    let mut entries: Vec<HttpCertificationTreeEntry> = request_urls(asset.id)
+        .iter()
+        .map(|url| HttpCertificationTreeEntry::new(path.clone(), options_certification(url)))
+        .collect();
+    entries.push(HttpCertificationTreeEntry::new(
+        path.clone(),
+        not_acceptable_certification(),
+    ));
+    for (encoding, variant) in asset.encodings.iter() {
+        entries.push(HttpCertificationTreeEntry::new(
+            path.clone(),
+            asset_certification(asset, *encoding, &variant),
+        ));
+        for url in request_urls(asset.id) {
+            entries.push(HttpCertificationTreeEntry::new(
+                path.clone(),
+                not_modified_certification(&variant, &url),
+            ));
+            entries.push(HttpCertificationTreeEntry::new(
+                path.clone(),
+                head_certification(asset, *encoding, &url),
+            ));
+        }
+    }
+    entries
+}
+
+fn fallback_entry() -> HttpCertificationTreeEntry<'static> {
+    HttpCertificationTreeEntry::new(
+        HttpCertificationPath::wildcard(""),
+        HttpCertification::skip(),
+    )
+}
+
+#[cfg(not(test))]
+fn set_root_hash(tree: &HttpCertificationTree) {
+    ic_cdk::api::set_certified_data(&tree.root_hash());
+}
+
+/// Unit tests run outside of a canister, which has no certified data to set.
+#[cfg(test)]
+fn set_root_hash(_tree: &HttpCertificationTree) {}
+
+/// Certificate over the root hash, only available in query calls.
+#[cfg(not(test))]
+fn data_certificate() -> Option<Vec<u8>> {
+    ic_cdk::api::data_certificate()
+}
+
+/// Unit tests run outside of a canister, so any bytes stand in for the certificate.
+#[cfg(test)]
+fn data_certificate() -> Option<Vec<u8>> {
+    Some(vec![])
+}
+
+/// Resets the tree to the uncertified fallback plus the entries of every public asset.
+///
+/// The fallback skips certification for every path that has no exact entry, so `404`s
+/// and other dynamic responses are still accepted by the certifying gateway.
+pub(crate) fn init_certification(assets: &SHashMap<u128, StableAsset>) {
+    HTTP_TREE.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        *tree = HttpCertificationTree::default();
+        tree.insert(&fallback_entry());
+        for (_, asset) in assets.iter() {
+            if asset.visibility != Visibility::Public {
+                continue;
+            }
+            for entry in asset_entries(&asset) {
+                tree.insert(&entry);
+            }
+        }
+        set_root_hash(&tree);
+    })
+}
+
+/// Adds the responses of `asset` to the tree. Only public assets are served over
+/// HTTP, so other assets are left to the uncertified fallback.
+pub(crate) fn certify_asset(asset: &StableAsset) {
+    if asset.visibility != Visibility::Public {
+        return;
+    }
+    HTTP_TREE.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        for entry in asset_entries(asset) {
+            tree.insert(&entry);
+        }
+        set_root_hash(&tree);
+    })
+}
+
+pub(crate) fn uncertify_asset(asset: &StableAsset) {
+    HTTP_TREE.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        for entry in asset_entries(asset) {
+            tree.delete(&entry);
+        }
+        set_root_hash(&tree);
+    })
+}
+
+fn to_cbor<T: Serialize>(value: &T) -> Vec<u8> {
+    let mut serializer = serde_cbor::Serializer::new(vec![]);
+    serializer.self_describe().unwrap();
+    value.serialize(&mut serializer).unwrap();
+    serializer.into_inner()
+}
+
+fn certificate_headers(
+    entry: &HttpCertificationTreeEntry,
+    request_path: &str,
+    expression: String,
+) -> Vec<HeaderField> {
+    let Some(certificate) = data_certificate() else {
+        return vec![];
+    };
+    let witness = HTTP_TREE.with(|tree| tree.borrow().witness(entry, request_path));
+    let Ok(witness) = witness else {
+        return vec![];
+    };
+    let value = format!(
+        "certificate=:{}:, tree=:{}:, expr_path=:{}:, version=2",
+        BASE64.encode(certificate),
+        BASE64.encode(to_cbor(&witness)),
+        BASE64.encode(to_cbor(&entry.path.to_expr_path())),
+    );
+    vec![
+        HeaderField(CERTIFICATE_HEADER.to_string(), value),
+        HeaderField(CERTIFICATE_EXPRESSION_HEADER.to_string(), expression),
+    ]
+}
+
+/// Headers proving a full `200` response carrying the whole content of `asset` in
+/// `encoding`.
+pub(crate) fn asset_certificate_headers(
+    asset: &StableAsset,
+    encoding: ContentEncoding,
+    variant: &StableEncoding,
+) -> Vec<HeaderField> {
+    let path = asset_path(asset.id);
+    let entry = HttpCertificationTreeEntry::new(
+        HttpCertificationPath::exact(path.clone()),
+        asset_certification(asset, encoding, variant),
+    );
+    certificate_headers(&entry, &path, asset_expression().to_string())
+}
+
+/// Headers proving the empty `200` response to a `HEAD` request for `asset` in
+/// `encoding`, sent to `request_url`.
+pub(crate) fn head_certificate_headers(
+    asset: &StableAsset,
+    encoding: ContentEncoding,
+    request_url: &str,
+) -> Vec<HeaderField> {
+    let path = asset_path(asset.id);
+    let entry = HttpCertificationTreeEntry::new(
+        HttpCertificationPath::exact(path.clone()),
+        head_certification(asset, encoding, request_url),
+    );
+    certificate_headers(&entry, &path, head_expression().to_string())
+}
+
+/// Headers proving the `204` response to an `OPTIONS` request for the public asset
+/// `asset_id`, sent to `request_url`.
+pub(crate) fn options_certificate_headers(asset_id: u128, request_url: &str) -> Vec<HeaderField> {
+    let path = asset_path(asset_id);
+    let entry = HttpCertificationTreeEntry::new(
+        HttpCertificationPath::exact(path.clone()),
+        options_certification(request_url),
+    );
+    certificate_headers(&entry, &path, options_expression().to_string())
+}
+
+/// Headers proving the `304 Not Modified` response to a `GET` for one encoding of
+/// `asset`, sent to `request_url` with exactly the entity tag of `variant` in
+/// `If-None-Match`.
+pub(crate) fn not_modified_certificate_headers(
+    asset: &StableAsset,
+    variant: &StableEncoding,
+    request_url: &str,
+) -> Vec<HeaderField> {
+    let path = asset_path(asset.id);
+    let entry = HttpCertificationTreeEntry::new(
+        HttpCertificationPath::exact(path.clone()),
+        not_modified_certification(variant, request_url),
+    );
+    certificate_headers(&entry, &path, not_modified_expression().to_string())
+}
+
+/// Headers proving the `406 Not Acceptable` response for the public asset `asset_id`.
+pub(crate) fn not_acceptable_certificate_headers(asset_id: u128) -> Vec<HeaderField> {
+    let path = asset_path(asset_id);
+    let entry = HttpCertificationTreeEntry::new(
+        HttpCertificationPath::exact(path.clone()),
+        not_acceptable_certification(),
+    );
+    certificate_headers(&entry, &path, not_acceptable_expression().to_string())
+}
+
+/// Headers telling the gateway that the response to `request_path` is not certified.
+pub(crate) fn fallback_certificate_headers(request_path: &str) -> Vec<HeaderField> {
+    certificate_headers(
+        &fallback_entry(),
+        request_path,
+        DefaultCelBuilder::skip_certification().to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::test_asset;
+    use ic_stable_memory::stable_memory_init;
+
+    #[test]
+    fn certifies_head_requests_with_any_query() {
+        stable_memory_init();
+        let asset = test_asset(1, b"hello");
+        let entries = asset_entries(&asset);
+        let certified = |url: &str| {
+            let entry = HttpCertificationTreeEntry::new(
+                HttpCertificationPath::exact(asset_path(1)),
+                head_certification(&asset, ContentEncoding::Identity, url),
+            );
+            entries.contains(&entry)
+        };
+
+        assert!(certified("/asset/1"));
+        assert!(certified("/asset/1?download=1"));
+        assert!(certified("/asset/1?a=1&b=2"));
+    }
+
+    #[test]
+    fn certifies_options_requests() {
+        stable_memory_init();
+        let asset = test_asset(1, b"hello");
+        let entries = asset_entries(&asset);
+        let certified = |url: &str| {
+            let entry = HttpCertificationTreeEntry::new(
+                HttpCertificationPath::exact(asset_path(1)),
+                options_certification(url),
+            );
+            entries.contains(&entry)
+        };
+
+        assert!(certified("/asset/1"));
+        assert!(certified("/asset/1?x=1"));
+    }
+
+    #[test]
+    fn certifies_not_modified_for_the_entity_tag_only() {
+        stable_memory_init();
+        let asset = test_asset(1, b"hello");
+        let variant = asset.encodings.get(&ContentEncoding::Identity).unwrap();
+        let entries = asset_entries(&asset);
+        let certified = |if_none_match: String| {
+            let expression = not_modified_expression();
+            let request = CertifiedRequest {
+                method: "GET".to_string(),
+                url: "/asset/1?x=1".to_string(),
+                headers: vec![("if-none-match".to_string(), if_none_match)],
+                body: vec![],
+            };
+            let response = CertifiedResponse {
+                status_code: 304,
+                headers: vec![
+                    ("etag".to_string(), variant.etag()),
+                    (
+                        CERTIFICATE_EXPRESSION_HEADER.to_string(),
+                        expression.to_string(),
+                    ),
+                ],
+                body: vec![],
+                upgrade: None,
+            };
+            let certification =
+                HttpCertification::full(&expression, &request, &response, None).unwrap();
+            let entry = HttpCertificationTreeEntry::new(
+                HttpCertificationPath::exact(asset_path(1)),
+                certification,
+            );
+            entries.contains(&entry)
+        };
+
+        assert!(certified(variant.etag()));
+        assert!(!certified(format!("W/{}", variant.etag())));
+        assert!(!certified("*".to_string()));
+    }
+}
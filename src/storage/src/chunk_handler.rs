@@ -1,66 +1,185 @@
+use std::time::Duration;
+
 use candid::{candid_method, CandidType};
 use ic_cdk_macros::{query, update};
 
-use crate::{memory::STATE, types::{StableChunk, ChunkQuery}};
+use crate::{
+    access_control::{is_admin, is_uploader},
+    memory::STATE,
+    quota::{charge, check_bytes, release},
+    types::{ChunkQuery, CleanupStats, StableChunk, StableSha256, StorageError},
+    upload_handler::hash_chunks,
+};
 
 #[derive(CandidType, serde::Deserialize)]
-pub struct ChunkArg {
-    pub order: u32,
+pub struct UploadChunkArg {
+    pub upload_id: u128,
+    pub index: u32,
     pub content: Vec<u8>,
+    /// SHA-256 of `content`, checked before the chunk is stored.
+    pub sha256: Vec<u8>,
 }
 
-#[update]
+/// Stores one chunk of an upload session. Uploading an index again replaces the chunk
+/// previously stored for it, so failed requests can simply be retried. Chunks but the
+/// last have to hold at least `MIN_CHUNK_SIZE` bytes, or the indices run out.
+///
+/// Chunks are hashed as they arrive in order, so committing only has to finish the
+/// SHA-256 of the file; replacing a hashed chunk starts it over.
+#[update(guard = "is_uploader")]
 #[candid_method(update)]
-pub fn upload_chunk(arg: ChunkArg) -> u128 {
+pub fn upload_chunk(arg: UploadChunkArg) -> Result<u128, StorageError> {
     let caller = ic_cdk::caller();
     STATE.with(|state| {
         let mut state = state.borrow_mut();
+        match state.uploads.get(&arg.upload_id) {
+            None => return Err(StorageError::UploadNotFound),
+            Some(upload) if upload.owner != caller => return Err(StorageError::NotOwner),
+            Some(upload) if arg.index >= upload.max_chunk_count() => {
+                return Err(StorageError::ChunkIndexOutOfRange {
+                    limit: upload.max_chunk_count(),
+                })
+            }
+            Some(_) => {}
+        }
+
+        let (upload_id, index) = (arg.upload_id, arg.index);
+        let size = arg.content.len() as u64;
+        let replaced_size = state
+            .uploads
+            .get(&upload_id)
+            .and_then(|upload| upload.chunks.get(&index).map(|id| *id))
+            .and_then(|id| {
+                state
+                    .chunks
+                    .get(&id)
+                    .map(|chunk| chunk.content.len() as u64)
+            })
+            .unwrap_or(0);
+        check_bytes(&state, &caller, size.saturating_sub(replaced_size))?;
+
         let id = state.get_chunk_id();
-        let chunk = StableChunk::from((&caller, id, arg));
-        state.chunks.insert(id, chunk).expect("failed to insert");
-        id
+        let chunk = StableChunk::try_from((&caller, id, arg))?;
+        state
+            .chunks
+            .insert(id, chunk)
+            .map_err(|_| StorageError::OutOfMemory)?;
+
+        let mut upload = state.uploads.get_mut(&upload_id).unwrap();
+        let replaced = match upload.chunks.insert(index, id) {
+            Ok(replaced) => replaced,
+            Err(_) => {
+                drop(upload);
+                state.chunks.remove(&id);
+                return Err(StorageError::OutOfMemory);
+            }
+        };
+        upload.updated_at = ic_cdk::api::time();
+        if index < upload.hashed_chunks {
+            upload.hasher = StableSha256::default();
+            upload.hashed_chunks = 0;
+        }
+        drop(upload);
+        hash_chunks(&mut state, upload_id);
+
+        charge(&mut state, &caller, size, 0);
+        if let Some(replaced) = replaced {
+            state.chunks.remove(&replaced);
+            release(&mut state, &caller, replaced_size, 0);
+        }
+        Ok(id)
     })
 }
 
-#[query]
+#[query(guard = "is_uploader")]
 #[candid_method(query)]
 pub fn chunk_availability_check(ids: Vec<u128>) -> bool {
     STATE.with(|state| {
         let state = state.borrow();
         for id in ids.iter() {
-            if let None = state.chunks.get(id){
-                return false
+            if let None = state.chunks.get(id) {
+                return false;
             }
         }
         true
     })
 }
 
-/// delete chunks that is stored for more than 10 mins
-#[update]
-#[candid_method(update)]
-pub fn clear_expired_chunks() {
-    let time = ic_cdk::api::time() - 10 * 60 * 1000_000;
+/// Instructions one cleanup run may use, well below the per-message limit.
+const CLEANUP_INSTRUCTION_BUDGET: u64 = 1_000_000_000;
+/// Time between two scheduled cleanup runs.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Starts the periodic cleanup. Timers do not survive upgrades, so this has to run on
+/// both init and post_upgrade.
+pub(crate) fn schedule_cleanup() {
+    ic_cdk_timers::set_timer_interval(CLEANUP_INTERVAL, run_scheduled_cleanup);
+}
+
+fn run_scheduled_cleanup() {
+    let stats = remove_expired_uploads(CLEANUP_INSTRUCTION_BUDGET);
+    if !stats.finished {
+        // continue in a fresh message instead of waiting for the next interval
+        ic_cdk_timers::set_timer(Duration::ZERO, run_scheduled_cleanup);
+    }
+}
+
+/// Removes upload sessions that received no chunk within the configured TTL, along
+/// with their chunks, until `budget` instructions are used.
+fn remove_expired_uploads(budget: u64) -> CleanupStats {
+    let now = ic_cdk::api::time();
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        let mut chunks_to_delete = vec![];
-        state.chunks.iter().for_each(|(id, chunk)| {
-            if chunk.created_at < time {
-                chunks_to_delete.push(id.clone());
+        let ttl = state.config.upload_ttl;
+        let expired: Vec<u128> = state
+            .uploads
+            .iter()
+            .filter(|(_, upload)| upload.updated_at.saturating_add(ttl) < now)
+            .map(|(id, _)| *id)
+            .collect();
+
+        let mut stats = CleanupStats {
+            finished: true,
+            ..Default::default()
+        };
+        for id in expired {
+            if ic_cdk::api::instruction_counter() > budget {
+                stats.finished = false;
+                break;
+            }
+            let Some(upload) = state.uploads.remove(&id) else {
+                continue;
+            };
+            let mut bytes = 0;
+            for (_, chunk_id) in upload.chunks.iter() {
+                if let Some(chunk) = state.chunks.remove(&chunk_id) {
+                    stats.chunks += 1;
+                    bytes += chunk.content.len() as u64;
+                }
             }
-        });
-        let _ = chunks_to_delete.iter().map(|id| state.chunks.remove(id));
-    });
+            release(&mut state, &upload.owner, bytes, 0);
+            stats.uploads += 1;
+            stats.bytes += bytes;
+        }
+        stats
+    })
+}
+
+/// Runs one cleanup right away instead of waiting for the timer.
+#[update(guard = "is_admin")]
+#[candid_method(update)]
+pub fn clear_expired_chunks() -> CleanupStats {
+    remove_expired_uploads(CLEANUP_INSTRUCTION_BUDGET)
 }
 
-#[query]
+#[query(guard = "is_uploader")]
 #[candid_method(query)]
-pub fn get_chunk(id: u128) -> ChunkQuery{
-    STATE.with(|state|{
+pub fn get_chunk(id: u128) -> Result<ChunkQuery, StorageError> {
+    STATE.with(|state| {
         let state = state.borrow();
-        match state.chunks.get(&id){
-            None => ic_cdk::trap("Chunk not found"),
-            Some(chunk) => ChunkQuery::from(&*chunk)
+        match state.chunks.get(&id) {
+            None => Err(StorageError::ChunkNotFound),
+            Some(chunk) => Ok(ChunkQuery::from(&*chunk)),
         }
     })
-}
\ No newline at end of file
+}
@@ -0,0 +1,741 @@
+use std::{
+    cell::{Cell, RefCell},
+    io::{self, Read, Write},
+    time::Duration,
+};
+
+use brotli_decompressor::{
+    BrotliDecompressStream, BrotliResult, BrotliState, DecompressorWriter, StandardAlloc,
+};
+use flate2::{
+    read::{MultiGzDecoder, ZlibDecoder},
+    write::{self, GzEncoder, ZlibEncoder},
+    Compression,
+};
+use ic_stable_memory::collections::{SHashMap, SVec};
+use ruzstd::decoding::{
+    errors::{FrameDecoderError, ReadFrameHeaderError},
+    FrameDecoder, StreamingDecoder,
+};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    certification::{certify_asset, uncertify_asset},
+    memory::STATE,
+    quota::{charge, check_bytes},
+    types::{ContentEncoding, StableEncoding, State},
+};
+
+/// Instructions one compression run may use, well below the per-message limit.
+const COMPRESSION_INSTRUCTION_BUDGET: u64 = 5_000_000_000;
+
+/// Whether assets of `content_type` are text-like enough to be worth compressing.
+pub(crate) fn is_compressible(content_type: &str) -> bool {
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    matches!(
+        media_type.as_str(),
+        "application/json"
+            | "text/html"
+            | "text/css"
+            | "text/javascript"
+            | "application/javascript"
+            | "image/svg+xml"
+            | "text/csv"
+    ) || media_type.ends_with("+json")
+}
+
+/// Whether `prefix`, the start of some content, begins like a stream in `encoding`.
+///
+/// Only the stream header is looked at, so a corrupt stream can still pass.
+pub(crate) fn has_valid_header(encoding: ContentEncoding, prefix: &[u8]) -> bool {
+    match encoding {
+        ContentEncoding::Identity => true,
+        // magic bytes followed by the deflate method, RFC 1952 section 2.3
+        ContentEncoding::GZIP => prefix.starts_with(&[0x1f, 0x8b, 0x08]),
+        // deflate method and header checksum, RFC 1950 section 2.2
+        ContentEncoding::Deflate => match prefix {
+            [cmf, flg, ..] => cmf & 0x0f == 8 && (u16::from(*cmf) << 8 | u16::from(*flg)) % 31 == 0,
+            _ => false,
+        },
+        // brotli has no magic bytes, so decode as much as fits in a small buffer
+        ContentEncoding::Brotli => {
+            let mut state = BrotliState::new(
+                StandardAlloc::default(),
+                StandardAlloc::default(),
+                StandardAlloc::default(),
+            );
+            let mut output = [0; 1024];
+            let (mut available_in, mut input_offset) = (prefix.len(), 0);
+            let (mut available_out, mut output_offset, mut total_out) = (output.len(), 0, 0);
+            let result = BrotliDecompressStream(
+                &mut available_in,
+                &mut input_offset,
+                prefix,
+                &mut available_out,
+                &mut output_offset,
+                &mut output,
+                &mut total_out,
+                &mut state,
+            );
+            !prefix.is_empty() && !matches!(result, BrotliResult::ResultFailure)
+        }
+        ContentEncoding::Zstd => StreamingDecoder::new(prefix).is_ok(),
+    }
+}
+
+/// Decodes `encoded` into its identity content, giving up on invalid streams and on
+/// content longer than `limit`.
+pub(crate) fn decode(encoding: ContentEncoding, encoded: &[u8], limit: u64) -> Option<Vec<u8>> {
+    let decoder: Box<dyn Read + '_> = match encoding {
+        ContentEncoding::Identity => Box::new(encoded),
+        ContentEncoding::GZIP => Box::new(MultiGzDecoder::new(encoded)),
+        ContentEncoding::Deflate => Box::new(ZlibDecoder::new(encoded)),
+        ContentEncoding::Brotli => Box::new(brotli_decompressor::Decompressor::new(encoded, 4096)),
+        ContentEncoding::Zstd => Box::new(StreamingDecoder::new(encoded).ok()?),
+    };
+    let mut decoded = vec![];
+    decoder.take(limit + 1).read_to_end(&mut decoded).ok()?;
+    (decoded.len() as u64 <= limit).then_some(decoded)
+}
+
+/// Encodings generated for text-like assets uploaded as identity.
+const COMPRESSED_ENCODINGS: [ContentEncoding; 2] =
+    [ContentEncoding::GZIP, ContentEncoding::Deflate];
+
+/// Bytes of the source fed to the transcoder in one step.
+const SLICE_SIZE: usize = 8 * 1024;
+/// Most bytes a decoder may produce from a single slice. Deflate expands at most about
+/// 1032 times, so only degenerate brotli or zstd streams reach it.
+const MAX_SLICE_OUTPUT: usize = 16 * 1024 * 1024;
+/// Size of the chunks generated encodings are stored in, small enough for
+/// `download_chunk` to return one.
+const OUTPUT_CHUNK_SIZE: usize = 1024 * 1024;
+/// Longest zstd frame header, RFC 8878 section 3.1.1.1.
+const MAX_FRAME_HEADER_SIZE: usize = 18;
+
+/// Output of a decoder, failing instead of growing past `MAX_SLICE_OUTPUT`, so that a
+/// decompression bomb is abandoned rather than exhausting the heap.
+#[derive(Default)]
+struct Output(Vec<u8>);
+
+impl Write for Output {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        if self.0.len() + bytes.len() > MAX_SLICE_OUTPUT {
+            return Err(io::Error::other("slice expands too much"));
+        }
+        self.0.extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn invalid_data(error: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+/// Push decoder of zstd streams. `ruzstd` only decodes whole blocks, so input is held
+/// back until the next block is complete, and blocks are decoded one at a time to
+/// bound the output of each.
+#[derive(Default)]
+struct ZstdDecoder {
+    decoder: FrameDecoder,
+    input: Vec<u8>,
+    /// Bytes of a skippable frame still to be dropped.
+    skip: usize,
+    in_frame: bool,
+    /// Whether the current frame ends with a content checksum.
+    checksum: bool,
+    output: Output,
+}
+
+impl ZstdDecoder {
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.input.extend_from_slice(bytes);
+        self.decode(false)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.decode(true)?;
+        if self.in_frame || self.skip > 0 || !self.input.is_empty() {
+            return Err(invalid_data("truncated zstd stream"));
+        }
+        Ok(())
+    }
+
+    /// Decodes as much of the input as is complete. `last` tells that no more input
+    /// follows, so a frame header may be shorter than the longest possible one.
+    fn decode(&mut self, last: bool) -> io::Result<()> {
+        loop {
+            if self.skip > 0 {
+                let skipped = self.skip.min(self.input.len());
+                self.input.drain(..skipped);
+                self.skip -= skipped;
+                if self.skip > 0 {
+                    return Ok(());
+                }
+            }
+            if !self.in_frame {
+                if self.input.is_empty() || (self.input.len() < MAX_FRAME_HEADER_SIZE && !last) {
+                    return Ok(());
+                }
+                // content checksum flag of the frame header descriptor, after the magic
+                self.checksum = self.input.get(4).is_some_and(|d| d & 0b100 != 0);
+                let mut header = self.input.as_slice();
+                let result = self.decoder.reset(&mut header);
+                let read = self.input.len() - header.len();
+                self.input.drain(..read);
+                match result {
+                    Ok(()) => self.in_frame = true,
+                    Err(FrameDecoderError::ReadFrameHeaderError(
+                        ReadFrameHeaderError::SkipFrame { length, .. },
+                    )) => self.skip = length as usize,
+                    Err(error) => return Err(invalid_data(error)),
+                }
+                continue;
+            }
+
+            // block header: last block flag, block type and size, RFC 8878 section 3.1.1.2
+            let Some(&[a, b, c]) = self.input.get(..3) else {
+                return Ok(());
+            };
+            let header = u32::from_le_bytes([a, b, c, 0]);
+            let last_block = header & 1 == 1;
+            let content_size = match (header >> 1) & 0b11 {
+                // an RLE block repeats a single byte
+                1 => 1,
+                _ => (header >> 3) as usize,
+            };
+            let block_size = 3 + content_size + if last_block && self.checksum { 4 } else { 0 };
+            if self.input.len() < block_size {
+                return Ok(());
+            }
+            let (read, _) = self
+                .decoder
+                .decode_from_to(&self.input[..block_size], &mut [])
+                .map_err(invalid_data)?;
+            if read == 0 {
+                return Err(invalid_data("zstd block not decoded"));
+            }
+            self.input.drain(..read);
+            if let Some(bytes) = self.decoder.collect() {
+                self.output.write_all(&bytes)?;
+            }
+            if last_block {
+                self.in_frame = false;
+            }
+        }
+    }
+}
+
+/// Turns content in one encoding into another, one slice at a time, writing into a
+/// buffer that `Job::flush_output` empties.
+enum Transcoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+    Gunzip(write::MultiGzDecoder<Output>),
+    Inflate(write::ZlibDecoder<Output>),
+    Unbrotli(Box<DecompressorWriter<Output>>),
+    Unzstd(Box<ZstdDecoder>),
+}
+
+impl Transcoder {
+    /// Compresses identity content into one of `COMPRESSED_ENCODINGS`, or decodes
+    /// another encoding into identity content.
+    fn new(source: ContentEncoding, target: ContentEncoding) -> Option<Self> {
+        match (source, target) {
+            (ContentEncoding::Identity, ContentEncoding::GZIP) => Some(Transcoder::Gzip(
+                GzEncoder::new(vec![], Compression::default()),
+            )),
+            (ContentEncoding::Identity, ContentEncoding::Deflate) => Some(Transcoder::Deflate(
+                ZlibEncoder::new(vec![], Compression::default()),
+            )),
+            (ContentEncoding::GZIP, ContentEncoding::Identity) => Some(Transcoder::Gunzip(
+                write::MultiGzDecoder::new(Output::default()),
+            )),
+            (ContentEncoding::Deflate, ContentEncoding::Identity) => Some(Transcoder::Inflate(
+                write::ZlibDecoder::new(Output::default()),
+            )),
+            (ContentEncoding::Brotli, ContentEncoding::Identity) => Some(Transcoder::Unbrotli(
+                Box::new(DecompressorWriter::new(Output::default(), 4096)),
+            )),
+            (ContentEncoding::Zstd, ContentEncoding::Identity) => {
+                Some(Transcoder::Unzstd(Box::default()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Fails on invalid input and on slices expanding past `MAX_SLICE_OUTPUT`.
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match self {
+            Transcoder::Gzip(encoder) => encoder.write_all(bytes),
+            Transcoder::Deflate(encoder) => encoder.write_all(bytes),
+            Transcoder::Gunzip(decoder) => decoder.write_all(bytes),
+            Transcoder::Inflate(decoder) => decoder.write_all(bytes),
+            Transcoder::Unbrotli(decoder) => decoder.write_all(bytes),
+            Transcoder::Unzstd(decoder) => decoder.write_all(bytes),
+        }
+    }
+
+    /// Fails on invalid and on truncated input.
+    fn finish(&mut self) -> io::Result<()> {
+        match self {
+            Transcoder::Gzip(encoder) => encoder.try_finish(),
+            Transcoder::Deflate(encoder) => encoder.try_finish(),
+            Transcoder::Gunzip(decoder) => decoder.try_finish(),
+            Transcoder::Inflate(decoder) => decoder.try_finish(),
+            Transcoder::Unbrotli(decoder) => decoder.close(),
+            Transcoder::Unzstd(decoder) => decoder.finish(),
+        }
+    }
+
+    fn take_output(&mut self) -> Vec<u8> {
+        match self {
+            Transcoder::Gzip(encoder) => std::mem::take(encoder.get_mut()),
+            Transcoder::Deflate(encoder) => std::mem::take(encoder.get_mut()),
+            Transcoder::Gunzip(decoder) => std::mem::take(&mut decoder.get_mut().0),
+            Transcoder::Inflate(decoder) => std::mem::take(&mut decoder.get_mut().0),
+            Transcoder::Unbrotli(decoder) => std::mem::take(&mut decoder.get_mut().0),
+            Transcoder::Unzstd(decoder) => std::mem::take(&mut decoder.output.0),
+        }
+    }
+}
+
+/// The generation of an encoding in progress. Its output is already in stable memory,
+/// but only referenced from here, so it must be dropped before upgrades.
+struct Job {
+    asset_id: u128,
+    encoding: ContentEncoding,
+    /// Encoding read from: identity, unless identity is the one generated.
+    source: ContentEncoding,
+    /// SHA-256 of the content being read, to notice it being replaced.
+    source_sha256: [u8; 32],
+    next_index: u32,
+    /// Offset of the next slice within chunk `next_index`.
+    offset: usize,
+    transcoder: Transcoder,
+    /// Output not moved into a chunk yet.
+    pending: Vec<u8>,
+    content: SHashMap<u32, SVec<u8>>,
+    chunk_count: u32,
+    total_length: u64,
+    hasher: Sha256,
+}
+
+impl Job {
+    /// Moves the output produced so far into new chunks of `OUTPUT_CHUNK_SIZE`, and
+    /// whatever remains too if `last`.
+    fn flush_output(&mut self, last: bool) {
+        self.pending.extend(self.transcoder.take_output());
+        while self.pending.len() >= OUTPUT_CHUNK_SIZE || (last && !self.pending.is_empty()) {
+            let rest = self
+                .pending
+                .split_off(self.pending.len().min(OUTPUT_CHUNK_SIZE));
+            let bytes = std::mem::replace(&mut self.pending, rest);
+            let mut chunk = SVec::new_with_capacity(bytes.len())
+                .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+            bytes.iter().for_each(|b| {
+                chunk
+                    .push(*b)
+                    .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"))
+            });
+            self.content
+                .insert(self.chunk_count, chunk)
+                .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+            self.hasher.update(&bytes);
+            self.chunk_count += 1;
+            self.total_length += bytes.len() as u64;
+        }
+    }
+}
+
+enum Progress {
+    Running,
+    Finished,
+    /// The asset or the content read went away while the encoding was generated, the
+    /// content turned out invalid, or the output outgrew the owner's quota.
+    Abandoned,
+}
+
+thread_local! {
+    static JOB: RefCell<Option<Job>> = RefCell::default();
+    static SCHEDULED: Cell<bool> = const { Cell::new(false) };
+}
+
+fn queue(state: &mut State, asset_id: u128, encoding: ContentEncoding) {
+    state
+        .pending_encodings
+        .push((asset_id, encoding))
+        .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+}
+
+/// Queues every compressed encoding to be generated for the identity content of
+/// `asset_id`.
+pub(crate) fn queue_compression(state: &mut State, asset_id: u128) {
+    for encoding in COMPRESSED_ENCODINGS {
+        queue(state, asset_id, encoding);
+    }
+    schedule_compression();
+}
+
+/// Queues the identity content of `asset_id` to be decoded from the encoding it was
+/// uploaded in, so that clients accepting no stored encoding get responses of any
+/// length, certified on the certified path.
+pub(crate) fn queue_decoding(state: &mut State, asset_id: u128) {
+    queue(state, asset_id, ContentEncoding::Identity);
+    schedule_compression();
+}
+
+/// Makes sure a run is coming up. Timers do not survive upgrades, so post_upgrade has
+/// to call this again while compressions are pending.
+pub(crate) fn schedule_compression() {
+    if !SCHEDULED.replace(true) {
+        ic_cdk_timers::set_timer(Duration::ZERO, run_compression);
+    }
+}
+
+/// Drops the running job; it starts over from the queue after the upgrade.
+pub(crate) fn abort_compression() {
+    JOB.with(|job| job.take());
+}
+
+fn run_compression() {
+    SCHEDULED.set(false);
+    let finished =
+        STATE.with(|state| compress(&mut state.borrow_mut(), COMPRESSION_INSTRUCTION_BUDGET));
+    if !finished {
+        // continue in a fresh message with a fresh instruction limit
+        schedule_compression();
+    }
+}
+
+/// Works through the queue, one slice at a time, until it is empty or `budget`
+/// instructions are used. Returns whether the queue is empty.
+fn compress(state: &mut State, budget: u64) -> bool {
+    JOB.with(|job| {
+        let mut job = job.borrow_mut();
+        loop {
+            if ic_cdk::api::instruction_counter() > budget {
+                return false;
+            }
+            let Some(running) = job.as_mut() else {
+                let Some((asset_id, encoding)) = state.pending_encodings.get(0).map(|e| *e) else {
+                    return true;
+                };
+                *job = start(state, asset_id, encoding);
+                if job.is_none() {
+                    state.pending_encodings.remove(0);
+                }
+                continue;
+            };
+            match step(state, running) {
+                Progress::Running => {}
+                Progress::Finished => {
+                    store(state, job.take().unwrap());
+                    state.pending_encodings.remove(0);
+                }
+                Progress::Abandoned => {
+                    job.take();
+                    state.pending_encodings.remove(0);
+                }
+            }
+        }
+    })
+}
+
+/// Sets up the job generating `encoding` for `asset_id`, unless there is nothing to
+/// generate it from anymore. Identity content is decoded from the first encoding
+/// stored, other encodings are compressed from identity content.
+fn start(state: &State, asset_id: u128, encoding: ContentEncoding) -> Option<Job> {
+    let asset = state.assets.get(&asset_id)?;
+    if asset.encodings.contains_key(&encoding) {
+        return None;
+    }
+    let source = match encoding {
+        ContentEncoding::Identity => asset.default_encoding(),
+        _ => ContentEncoding::Identity,
+    };
+    let source_sha256 = asset.encodings.get(&source)?.sha256;
+    Some(Job {
+        asset_id,
+        encoding,
+        source,
+        source_sha256,
+        next_index: 0,
+        offset: 0,
+        transcoder: Transcoder::new(source, encoding)?,
+        pending: vec![],
+        content: SHashMap::new(),
+        chunk_count: 0,
+        total_length: 0,
+        hasher: Sha256::new(),
+    })
+}
+
+/// Feeds the next slice of the source content to the transcoder.
+fn step(state: &State, job: &mut Job) -> Progress {
+    let Some(asset) = state.assets.get(&job.asset_id) else {
+        return Progress::Abandoned;
+    };
+    let Some(source) = asset
+        .encodings
+        .get(&job.source)
+        .filter(|source| source.sha256 == job.source_sha256)
+    else {
+        return Progress::Abandoned;
+    };
+    if job.next_index == source.chunk_count {
+        if job.transcoder.finish().is_err() {
+            return Progress::Abandoned;
+        }
+        job.flush_output(true);
+        return Progress::Finished;
+    }
+    let slice: Vec<u8> = match source.content.get(&job.next_index) {
+        Some(chunk) => {
+            let end = chunk.len().min(job.offset + SLICE_SIZE);
+            (job.offset..end).map(|i| *chunk.get(i).unwrap()).collect()
+        }
+        None => vec![],
+    };
+    if slice.is_empty() {
+        job.next_index += 1;
+        job.offset = 0;
+        return Progress::Running;
+    }
+    job.offset += slice.len();
+    if job.transcoder.write_all(&slice).is_err() {
+        return Progress::Abandoned;
+    }
+    job.flush_output(false);
+    // decoded content can be far larger than what it is decoded from
+    let generated = job.total_length + job.pending.len() as u64;
+    if check_bytes(state, &asset.owner, generated).is_err() {
+        return Progress::Abandoned;
+    }
+    Progress::Running
+}
+
+/// Adds the finished encoding to its asset, if it fits in the owner's quota and, unless
+/// it is the identity content, is smaller than the identity content.
+fn store(state: &mut State, job: Job) {
+    let Some(asset) = state.assets.get(&job.asset_id) else {
+        return;
+    };
+    let owner = asset.owner;
+    let smaller = job.encoding == ContentEncoding::Identity
+        || asset
+            .encodings
+            .get(&ContentEncoding::Identity)
+            .is_some_and(|identity| job.total_length < identity.total_length);
+    // an encoding uploaded in the meantime takes precedence
+    let uploaded = asset.encodings.contains_key(&job.encoding);
+    drop(asset);
+    if !smaller || uploaded || check_bytes(state, &owner, job.total_length).is_err() {
+        return;
+    }
+
+    let encoding = StableEncoding {
+        content: job.content,
+        chunk_count: job.chunk_count,
+        total_length: job.total_length,
+        sha256: job.hasher.finalize().into(),
+    };
+    let mut asset = state.assets.get_mut(&job.asset_id).unwrap();
+    uncertify_asset(&asset);
+    asset
+        .encodings
+        .insert(job.encoding, encoding)
+        .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    certify_asset(&asset);
+    drop(asset);
+    charge(state, &owner, job.total_length, 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compresses_text_like_content_types() {
+        assert!(is_compressible("application/json"));
+        assert!(is_compressible("text/html; charset=utf-8"));
+        assert!(is_compressible("Image/SVG+XML"));
+        assert!(is_compressible("application/ld+json"));
+        assert!(!is_compressible("image/png"));
+        assert!(!is_compressible("video/mp4"));
+    }
+
+    /// Runs `input` through a transcoder in parts of `part_size` bytes.
+    fn transcode(
+        source: ContentEncoding,
+        target: ContentEncoding,
+        input: &[u8],
+        part_size: usize,
+    ) -> io::Result<Vec<u8>> {
+        let mut transcoder = Transcoder::new(source, target).unwrap();
+        let mut output = vec![];
+        for part in input.chunks(part_size) {
+            transcoder.write_all(part)?;
+            output.extend(transcoder.take_output());
+        }
+        transcoder.finish()?;
+        output.extend(transcoder.take_output());
+        Ok(output)
+    }
+
+    #[test]
+    fn compresses_into_each_encoding() {
+        let text = b"hello hello hello hello";
+        for encoding in COMPRESSED_ENCODINGS {
+            let output = transcode(ContentEncoding::Identity, encoding, text, 10).unwrap();
+
+            assert!(has_valid_header(encoding, &output));
+            assert_eq!(decode(encoding, &output, 100).unwrap(), text);
+        }
+        assert!(Transcoder::new(ContentEncoding::Identity, ContentEncoding::Brotli).is_none());
+        assert!(Transcoder::new(ContentEncoding::GZIP, ContentEncoding::Deflate).is_none());
+    }
+
+    /// Brotli stream of `bytes`, which must not be empty, in uncompressed meta-blocks
+    /// (RFC 7932 section 9.2), as there is no brotli encoder among the dependencies.
+    fn stored_brotli(bytes: &[u8]) -> Vec<u8> {
+        let mut stream = vec![];
+        for (index, block) in bytes.chunks(1 << 16).enumerate() {
+            // ISLAST = 0, MNIBBLES = 4, MLEN - 1 and ISUNCOMPRESSED, padded to a byte;
+            // the first one follows WBITS = 16, a single zero bit
+            let header = (block.len() as u32 - 1) << 3 | 1 << 19;
+            let header = if index == 0 { header << 1 } else { header };
+            stream.extend(&header.to_le_bytes()[..3]);
+            stream.extend(block);
+        }
+        // an empty last meta-block: ISLAST and ISLASTEMPTY
+        stream.push(0b11);
+        stream
+    }
+
+    #[test]
+    fn decodes_each_encoding() {
+        let text: Vec<u8> = (0..300_000u32)
+            .flat_map(|i| (i % 251).to_string().into_bytes())
+            .collect();
+        let gzip = transcode(
+            ContentEncoding::Identity,
+            ContentEncoding::GZIP,
+            &text,
+            4096,
+        )
+        .unwrap();
+        let zlib = transcode(
+            ContentEncoding::Identity,
+            ContentEncoding::Deflate,
+            &text,
+            4096,
+        )
+        .unwrap();
+        let frame = ruzstd::encoding::compress_to_vec(
+            &text[..],
+            ruzstd::encoding::CompressionLevel::Fastest,
+        );
+        // a skippable frame, then the same frame twice
+        let mut zstd = vec![0x50, 0x2a, 0x4d, 0x18, 3, 0, 0, 0, 1, 2, 3];
+        zstd.extend(&frame);
+        zstd.extend(&frame);
+        let twice = [&text[..], &text[..]].concat();
+
+        let identity = ContentEncoding::Identity;
+        for part_size in [7, SLICE_SIZE] {
+            assert_eq!(
+                transcode(ContentEncoding::GZIP, identity, &gzip, part_size).unwrap(),
+                text
+            );
+            assert_eq!(
+                transcode(ContentEncoding::Deflate, identity, &zlib, part_size).unwrap(),
+                text
+            );
+            assert_eq!(
+                transcode(ContentEncoding::Zstd, identity, &zstd, part_size).unwrap(),
+                twice
+            );
+        }
+
+        let brotli = stored_brotli(&text);
+        assert_eq!(
+            transcode(ContentEncoding::Brotli, identity, &brotli, 7).unwrap(),
+            text
+        );
+        assert_eq!(
+            transcode(ContentEncoding::Brotli, identity, &brotli, SLICE_SIZE).unwrap(),
+            text
+        );
+        assert_eq!(
+            decode(ContentEncoding::Brotli, &brotli, text.len() as u64).unwrap(),
+            text
+        );
+        let truncated = &brotli[..brotli.len() - 1];
+        assert!(transcode(ContentEncoding::Brotli, identity, truncated, SLICE_SIZE).is_err());
+
+        let truncated = &frame[..frame.len() - 1];
+        assert!(transcode(ContentEncoding::Zstd, identity, truncated, SLICE_SIZE).is_err());
+        assert!(transcode(ContentEncoding::GZIP, identity, &zlib, SLICE_SIZE).is_err());
+    }
+
+    #[test]
+    fn abandons_slices_expanding_too_much() {
+        let zeros = vec![0; MAX_SLICE_OUTPUT + OUTPUT_CHUNK_SIZE];
+        let gzip = transcode(
+            ContentEncoding::Identity,
+            ContentEncoding::GZIP,
+            &zeros,
+            1 << 20,
+        )
+        .unwrap();
+        // written whole rather than slice by slice, so it is larger than any one slice
+        assert!(gzip.len() > SLICE_SIZE);
+
+        let mut transcoder =
+            Transcoder::new(ContentEncoding::GZIP, ContentEncoding::Identity).unwrap();
+        assert!(transcoder.write_all(&gzip).is_err());
+    }
+
+    #[test]
+    fn checks_stream_headers() {
+        let text = b"hello hello hello hello";
+        let mut gzip = GzEncoder::new(vec![], Compression::default());
+        gzip.write_all(text).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let mut zlib = flate2::write::ZlibEncoder::new(vec![], Compression::default());
+        zlib.write_all(text).unwrap();
+        let zlib = zlib.finish().unwrap();
+        let zstd = ruzstd::encoding::compress_to_vec(
+            &text[..],
+            ruzstd::encoding::CompressionLevel::Fastest,
+        );
+
+        assert!(has_valid_header(ContentEncoding::GZIP, &gzip));
+        assert!(has_valid_header(ContentEncoding::Deflate, &zlib));
+        assert!(has_valid_header(ContentEncoding::Zstd, &zstd));
+        assert!(!has_valid_header(ContentEncoding::GZIP, &zlib));
+        assert!(!has_valid_header(ContentEncoding::Deflate, &gzip));
+        assert!(!has_valid_header(ContentEncoding::Zstd, text));
+        assert!(!has_valid_header(ContentEncoding::Brotli, &[]));
+
+        let limit = text.len() as u64;
+        assert_eq!(decode(ContentEncoding::GZIP, &gzip, limit).unwrap(), text);
+        assert_eq!(
+            decode(ContentEncoding::Deflate, &zlib, limit).unwrap(),
+            text
+        );
+        assert_eq!(decode(ContentEncoding::Zstd, &zstd, limit).unwrap(), text);
+        assert_eq!(decode(ContentEncoding::GZIP, &gzip, limit - 1), None);
+        assert_eq!(decode(ContentEncoding::GZIP, &zlib, limit), None);
+    }
+}
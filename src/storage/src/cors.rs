@@ -0,0 +1,157 @@
+use candid::candid_method;
+use ic_cdk_macros::{query, update};
+
+use crate::{
+    access_control::{is_admin, is_reader},
+    memory::STATE,
+    types::{
+        Cors, HeaderField, StableCors, StorageError, DEFAULT_CORS_HEADERS, DEFAULT_CORS_METHODS,
+    },
+};
+
+/// Response headers scripts on other origins may read besides the safelisted ones.
+const EXPOSED_HEADERS: &str =
+    "Accept-Ranges, Content-Disposition, Content-Encoding, Content-Range, ETag";
+
+/// Whether `c` may appear in a method or header name, RFC 9110 section 5.6.2.
+fn is_token_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)
+}
+
+fn check_cors(cors: &Cors) -> Result<(), StorageError> {
+    let valid_origin = |origin: &String| {
+        origin == "*"
+            || ((origin.starts_with("https://") || origin.starts_with("http://"))
+                && !origin.ends_with('/')
+                && !origin
+                    .chars()
+                    .any(|c| c.is_ascii_control() || c.is_whitespace() || c == ','))
+    };
+    let valid_token = |token: &String| !token.is_empty() && token.chars().all(is_token_char);
+    if cors.allowed_origins.iter().all(valid_origin)
+        && cors.allowed_methods.iter().all(valid_token)
+        && cors.allowed_headers.iter().all(valid_token)
+    {
+        Ok(())
+    } else {
+        Err(StorageError::InvalidCors)
+    }
+}
+
+/// Value of `Access-Control-Allow-Origin` for a request from `origin`, if it is allowed.
+fn allow_origin(cors: &Cors, origin: &str) -> Option<String> {
+    if cors
+        .allowed_origins
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+    {
+        Some(origin.to_string())
+    } else if cors.allowed_origins.iter().any(|allowed| allowed == "*") {
+        Some("*".to_string())
+    } else {
+        None
+    }
+}
+
+fn current_cors() -> Cors {
+    STATE.with(|state| Cors::from(&state.borrow().config.cors))
+}
+
+/// `Access-Control-*` headers added to every response to a request from `origin`.
+pub(crate) fn cors_headers(origin: Option<&str>) -> Vec<HeaderField> {
+    let cors = current_cors();
+    let mut headers = vec![];
+    // the response depends on the origin unless every origin gets the same answer
+    if !cors.allowed_origins.is_empty() && cors.allowed_origins != ["*"] {
+        headers.push(HeaderField("Vary".to_string(), "Origin".to_string()));
+    }
+    if let Some(allowed) = origin.and_then(|origin| allow_origin(&cors, origin)) {
+        headers.extend([
+            HeaderField("Access-Control-Allow-Origin".to_string(), allowed),
+            HeaderField(
+                "Access-Control-Expose-Headers".to_string(),
+                EXPOSED_HEADERS.to_string(),
+            ),
+        ]);
+    }
+    headers
+}
+
+/// Headers answering a preflight request from `origin`, empty if it is not allowed.
+pub(crate) fn preflight_headers(origin: Option<&str>) -> Vec<HeaderField> {
+    let cors = current_cors();
+    if origin
+        .and_then(|origin| allow_origin(&cors, origin))
+        .is_none()
+    {
+        return vec![];
+    }
+    let methods = match cors.allowed_methods.is_empty() {
+        true => DEFAULT_CORS_METHODS.join(", "),
+        false => cors.allowed_methods.join(", "),
+    };
+    let headers = match cors.allowed_headers.is_empty() {
+        true => DEFAULT_CORS_HEADERS.join(", "),
+        false => cors.allowed_headers.join(", "),
+    };
+    vec![
+        HeaderField("Access-Control-Allow-Methods".to_string(), methods),
+        HeaderField("Access-Control-Allow-Headers".to_string(), headers),
+        HeaderField(
+            "Access-Control-Max-Age".to_string(),
+            cors.max_age.to_string(),
+        ),
+    ]
+}
+
+/// Sets which origins may fetch assets from a browser, and what their preflights allow.
+#[update(guard = "is_admin")]
+#[candid_method(update)]
+pub fn set_cors(cors: Cors) -> Result<(), StorageError> {
+    check_cors(&cors)?;
+    let cors = StableCors::try_from(cors)?;
+    STATE.with(|state| state.borrow_mut().config.cors = cors);
+    Ok(())
+}
+
+#[query(guard = "is_reader")]
+#[candid_method(query)]
+pub fn get_cors() -> Cors {
+    current_cors()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cors(origins: &[&str]) -> Cors {
+        Cors {
+            allowed_origins: origins.iter().map(|origin| origin.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn allows_configured_origins() {
+        let app = cors(&["https://app.example.com"]);
+        assert_eq!(
+            allow_origin(&app, "https://APP.example.com").as_deref(),
+            Some("https://APP.example.com")
+        );
+        assert_eq!(allow_origin(&app, "https://other.example.com"), None);
+        assert_eq!(allow_origin(&cors(&[]), "https://app.example.com"), None);
+        assert_eq!(
+            allow_origin(&cors(&["*"]), "https://other.example.com").as_deref(),
+            Some("*")
+        );
+
+        assert!(check_cors(&cors(&["https://app.example.com", "*"])).is_ok());
+        assert!(check_cors(&cors(&["https://app.example.com/"])).is_err());
+        assert!(check_cors(&cors(&["app.example.com"])).is_err());
+        let methods = Cors {
+            allowed_methods: vec!["GET, PUT".to_string()],
+            ..Default::default()
+        };
+        assert!(check_cors(&methods).is_err());
+    }
+}
@@ -1,45 +1,373 @@
-use crate::{memory::STATE, utils::get_asset_id, types::*};
-use candid::{Func, candid_method};
-use ic_cdk_macros::query;
+use crate::{
+    access_control::{is_admin, is_reader},
+    certification::{
+        asset_certificate_headers, asset_path, fallback_certificate_headers,
+        head_certificate_headers, not_acceptable_certificate_headers,
+        not_modified_certificate_headers, options_certificate_headers, NOT_ACCEPTABLE_BODY,
+    },
+    compression::decode,
+    cors::{cors_headers, preflight_headers},
+    memory::STATE,
+    router::{alias_of, route, Route, RouteError},
+    signed_url::{signature_from_url, verify},
+    types::*,
+    utils::{
+        content_disposition, get_path, get_query_param, http_date, parse_http_date, parse_query,
+        parse_range, select_encoding, RangeRequest,
+    },
+};
+use candid::{candid_method, Func, Principal};
+use ic_cdk_macros::{query, update};
+
+/// Whether the caller can read the asset, or presents a valid signed URL for it.
+fn is_authorized(state: &State, asset: &StableAsset, signature: Option<&UrlSignature>) -> bool {
+    if asset.can_read(&ic_cdk::caller()) {
+        return true;
+    }
+    match (&state.url_secret, signature) {
+        (Some(secret), Some(signature)) => verify(secret, asset, signature),
+        _ => false,
+    }
+}
+
+fn find_header<'a>(headers: &'a [HeaderField], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|HeaderField(header, _)| header.eq_ignore_ascii_case(name))
+        .map(|HeaderField(_, value)| value.as_str())
+}
+
+/// Whether the client's cached copy is still current, per `If-None-Match` or, only when
+/// that is absent, `If-Modified-Since`.
+fn is_not_modified(headers: &[HeaderField], asset: &StableAsset, variant: &StableEncoding) -> bool {
+    if let Some(tags) = find_header(headers, "if-none-match") {
+        let etag = variant.etag();
+        // `If-None-Match` uses the weak comparison
+        return tags
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag);
+    }
+    match find_header(headers, "if-modified-since").and_then(parse_http_date) {
+        Some(since) => asset.updated_at > 0 && asset.updated_at / 1_000_000_000 <= since,
+        None => false,
+    }
+}
+
+/// Plain error response, left to the uncertified fallback.
+fn error_response(status_code: u16, body: &[u8], path: &str) -> HttpResponse {
+    HttpResponse {
+        body: body.to_vec(),
+        status_code,
+        headers: fallback_certificate_headers(path),
+        streaming_strategy: None,
+    }
+}
+
+/// Serves the identity content of an asset that is only stored in `encoding`, decoding
+/// it on the fly, on paths left to the uncertified fallback. Such responses are not
+/// available as ranges, and the decoded content has to fit in a single body of
+/// `max_body_size`, as decoders cannot be resumed across streaming callbacks.
+fn decoded_response(
+    asset: &StableAsset,
+    encoding: ContentEncoding,
+    disposition: String,
+    max_body_size: u64,
+    path: &str,
+) -> HttpResponse {
+    let variant = asset.encodings.get(&encoding).unwrap();
+    let body = match variant.total_length {
+        0 => None,
+        length if length > max_body_size => None,
+        length => decode(encoding, &variant.read_range(0, length - 1), max_body_size),
+    };
+    let Some(body) = body else {
+        return error_response(406, b"Not Acceptable", path);
+    };
+
+    let mut headers = vec![
+        HeaderField("Content-Type".to_string(), asset.content_type.clone()),
+        HeaderField("Content-Disposition".to_string(), disposition),
+        HeaderField("cache-control".to_string(), asset.cache_control()),
+        HeaderField("Vary".to_string(), "Accept-Encoding".to_string()),
+        HeaderField("Content-Length".to_string(), body.len().to_string()),
+    ];
+    if asset.updated_at > 0 {
+        headers.push(HeaderField(
+            "Last-Modified".to_string(),
+            http_date(asset.updated_at),
+        ));
+    }
+    headers.extend(fallback_certificate_headers(path));
+    HttpResponse {
+        body,
+        status_code: 200,
+        headers,
+        streaming_strategy: None,
+    }
+}
+
+/// Certified `406` for clients accepting none of the encodings stored for a public
+/// asset, on its certified path.
+fn not_acceptable_response(asset_id: u128) -> HttpResponse {
+    let mut headers = vec![HeaderField(
+        "Vary".to_string(),
+        "Accept-Encoding".to_string(),
+    )];
+    headers.extend(not_acceptable_certificate_headers(asset_id));
+    HttpResponse {
+        body: NOT_ACCEPTABLE_BODY.to_vec(),
+        status_code: 406,
+        headers,
+        streaming_strategy: None,
+    }
+}
+
+/// Id of the asset `owner` most recently committed under `file_name`.
+fn find_by_name(state: &State, owner: &Principal, file_name: &str) -> Option<u128> {
+    state.names.get(&name_key(owner, file_name)).map(|id| *id)
+}
+
+/// Serves `/.well-known/ic-domains`, which the boundary nodes check before routing
+/// a custom domain to the canister.
+fn domains_response(state: &State, path: &str) -> HttpResponse {
+    let Some(domain) = &state.config.urls.custom_domain else {
+        return error_response(404, b"Not Found", path);
+    };
+    let mut headers = vec![HeaderField(
+        "Content-Type".to_string(),
+        "text/plain".to_string(),
+    )];
+    headers.extend(fallback_certificate_headers(path));
+    HttpResponse {
+        body: format!("{}\n", **domain).into_bytes(),
+        status_code: 200,
+        headers,
+        streaming_strategy: None,
+    }
+}
+
+/// Methods `http_request` answers, in the format of the `Allow` header.
+const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
 
 #[query]
 #[candid_method(query)]
 pub fn http_request(request: HttpRequest) -> HttpResponse {
-    let not_found = b"Asset Not Found".iter().map(|b| b.clone()).collect();
-    let asset_id = get_asset_id(request.url);
+    let path = get_path(&request.url).to_string();
+    let origin = find_header(&request.headers, "origin");
+    let mut response = match request.method.as_str() {
+        "GET" => get_response(&request, &path),
+        "HEAD" => HttpResponse {
+            body: vec![],
+            streaming_strategy: None,
+            ..get_response(&request, &path)
+        },
+        "OPTIONS" => {
+            let mut headers = vec![HeaderField(
+                "Allow".to_string(),
+                ALLOWED_METHODS.to_string(),
+            )];
+            headers.extend(preflight_headers(origin));
+            match certified_asset(&path) {
+                Some(asset_id) => {
+                    headers.extend(options_certificate_headers(asset_id, &request.url))
+                }
+                None => headers.extend(fallback_certificate_headers(&path)),
+            }
+            HttpResponse {
+                body: vec![],
+                status_code: 204,
+                headers,
+                streaming_strategy: None,
+            }
+        }
+        _ => {
+            let mut response = error_response(405, b"Method Not Allowed", &path);
+            response.headers.push(HeaderField(
+                "Allow".to_string(),
+                ALLOWED_METHODS.to_string(),
+            ));
+            response
+        }
+    };
+    response.headers.extend(cors_headers(origin));
+    response
+}
+
+/// Id of the public asset whose certified path is `path`, if there is one.
+fn certified_asset(path: &str) -> Option<u128> {
+    let Ok(Route::Asset(asset_id)) = route(path) else {
+        return None;
+    };
+    let public = STATE.with(|state| {
+        state
+            .borrow()
+            .assets
+            .get(&asset_id)
+            .is_some_and(|asset| asset.visibility == Visibility::Public)
+    });
+    (public && path == asset_path(asset_id)).then_some(asset_id)
+}
+
+/// Answers a `GET` for `path`; `HEAD` takes the same headers, but its own certificate.
+fn get_response(request: &HttpRequest, path: &str) -> HttpResponse {
+    let head = request.method == "HEAD";
+    if parse_query(&request.url).is_none() {
+        return error_response(400, b"Bad Request", path);
+    }
+    let signature = signature_from_url(&request.url);
     STATE.with(|state| {
         let state = state.borrow();
-        match state.assets.get(&asset_id) {
-            None => HttpResponse {
-                body: not_found,
-                status_code: 404,
-                headers: vec![],
+        // aliases take precedence, they cannot shadow the built-in routes anyway
+        let alias = alias_of(path).and_then(|alias| state.aliases.get(&alias).map(|id| *id));
+        let asset_id = match (alias, route(path)) {
+            (Some(id), _) | (None, Ok(Route::Asset(id))) => Some(id),
+            (None, Ok(Route::ByName { owner, file_name })) => {
+                find_by_name(&state, &owner, &file_name)
+            }
+            (None, Ok(Route::WellKnownDomains)) => return domains_response(&state, path),
+            (None, Err(RouteError::BadRequest)) => {
+                return error_response(400, b"Bad Request", path)
+            }
+            (None, Err(RouteError::NotFound)) => return error_response(404, b"Not Found", path),
+        };
+        let asset = match asset_id.and_then(|id| state.assets.get(&id)) {
+            None => return error_response(404, b"Asset Not Found", path),
+            Some(asset) if !is_authorized(&state, &asset, signature.as_ref()) => {
+                return error_response(403, b"Forbidden", path)
+            }
+            Some(asset) => asset,
+        };
+        // other paths to the asset fall back to being uncertified
+        let certified = asset.visibility == Visibility::Public && path == asset_path(asset.id);
+        // `?download=1` saves the asset whatever its own disposition
+        let disposition = match get_query_param(&request.url, "download").as_deref() {
+            Some("1" | "true") => Disposition::Attachment,
+            Some("0" | "false") => Disposition::Inline,
+            _ => asset.disposition,
+        };
+        let disposition = content_disposition(disposition, &asset.file_name);
+        let accept_encoding = find_header(&request.headers, "accept-encoding");
+        let stored = asset.stored_encodings();
+        let encoding = match select_encoding(accept_encoding, &stored) {
+            Some(encoding) => encoding,
+            // decoding on the fly cannot be certified, the other paths to the asset do it
+            None if certified => return not_acceptable_response(asset.id),
+            None if select_encoding(accept_encoding, &[ContentEncoding::Identity]).is_some() => {
+                let max_body_size = state.config.max_body_size;
+                return decoded_response(&asset, stored[0], disposition, max_body_size, path);
+            }
+            None => return error_response(406, b"Not Acceptable", path),
+        };
+        let variant = asset.encodings.get(&encoding).unwrap();
+
+        let mut headers = vec![
+            HeaderField("ETag".to_string(), variant.etag()),
+            HeaderField("cache-control".to_string(), asset.cache_control()),
+            HeaderField("Vary".to_string(), "Accept-Encoding".to_string()),
+        ];
+        // a `304` on the certified path is only certified for a `GET` naming exactly
+        // the entity tag in `If-None-Match`, other conditional requests get the content
+        let not_modified = match certified {
+            true => {
+                !head && find_header(&request.headers, "if-none-match") == Some(&variant.etag())
+            }
+            false => is_not_modified(&request.headers, &asset, &variant),
+        };
+        if not_modified {
+            if certified {
+                headers.extend(not_modified_certificate_headers(
+                    &asset,
+                    &variant,
+                    &request.url,
+                ));
+            } else {
+                headers.extend(fallback_certificate_headers(path));
+            }
+            return HttpResponse {
+                body: vec![],
+                status_code: 304,
+                headers,
                 streaming_strategy: None,
-            },
-            Some(asset) => {
-                let filename = format!("attachment; filename={}", asset.file_name.clone());
+            };
+        }
+
+        // the certified path always sends the whole content, as partial responses
+        // cannot be certified for every `Range` a client might send; the paths left to
+        // the fallback, like the `/asset/{id}/{file_name}` that `asset_url` hands out,
+        // serve ranges of the same content
+        let accept_ranges = if certified { "none" } else { "bytes" };
+        headers.extend([
+            HeaderField("Content-Type".to_string(), asset.content_type.clone()),
+            HeaderField("accept-ranges".to_string(), accept_ranges.to_string()),
+            HeaderField("Content-Disposition".to_string(), disposition),
+        ]);
+        if encoding != ContentEncoding::Identity {
+            headers.push(HeaderField(
+                "Content-Encoding".to_string(),
+                encoding.token().to_string(),
+            ));
+        }
+        if asset.updated_at > 0 {
+            headers.push(HeaderField(
+                "Last-Modified".to_string(),
+                http_date(asset.updated_at),
+            ));
+        }
+        let total_length = variant.total_length;
+        let range = match find_header(&request.headers, "range") {
+            Some(range) if !certified => parse_range(range, total_length),
+            _ => RangeRequest::Full,
+        };
+        match range {
+            RangeRequest::Unsatisfiable => {
+                headers.push(HeaderField(
+                    "Content-Range".to_string(),
+                    format!("bytes */{total_length}"),
+                ));
+                headers.extend(fallback_certificate_headers(path));
+                HttpResponse {
+                    body: vec![],
+                    status_code: 416,
+                    headers,
+                    streaming_strategy: None,
+                }
+            }
+            RangeRequest::Partial { start, end } => partial_response(
+                headers,
+                &variant,
+                start,
+                end,
+                state.config.max_body_size,
+                path,
+            ),
+            RangeRequest::Full => {
+                headers.push(HeaderField(
+                    "Content-Length".to_string(),
+                    total_length.to_string(),
+                ));
+                if !certified {
+                    headers.extend(fallback_certificate_headers(path));
+                } else if head {
+                    headers.extend(head_certificate_headers(&asset, encoding, &request.url));
+                } else {
+                    headers.extend(asset_certificate_headers(&asset, encoding, &variant));
+                }
+                let (body, next) = match head {
+                    true => (vec![], None),
+                    false => read_window(&variant, 0, state.config.max_body_size),
+                };
                 HttpResponse {
-                    body: asset
-                        .content
-                        .get(&0)
-                        .unwrap()
-                        .iter()
-                        .map(|b| b.clone())
-                        .collect(),
+                    body,
                     status_code: 200,
-                    headers: vec![
-                        HeaderField("Content-Type".to_string(), asset.content_type.clone()),
-                        HeaderField("accept-ranges".to_string(), "bytes".to_string()),
-                        HeaderField("Content-Disposition".to_string(), filename),
-                        HeaderField(
-                            "cache-control".to_string(),
-                            "private, max-age=0".to_string(),
-                        ),
-                    ],
-                    streaming_strategy: create_strategy(CreateStrategyArgs {
-                        asset_id: asset_id.clone(),
-                        chunk_index: 0,
-                        chunk_size: asset.chunk_size,
+                    headers,
+                    streaming_strategy: next.map(|offset| {
+                        create_strategy(CreateStrategyArgs {
+                            asset_id: asset.id,
+                            offset,
+                            content_encoding: encoding,
+                            signature,
+                        })
                     }),
                 }
             }
@@ -47,35 +375,69 @@ pub fn http_request(request: HttpRequest) -> HttpResponse {
     })
 }
 
-fn create_strategy(arg: CreateStrategyArgs) -> Option<StreamingStrategy> {
-    match create_token(arg) {
-        None => None,
-        Some(token) => {
-            let id = ic_cdk::id();
-            Some(StreamingStrategy::Callback {
-                token,
-                callback: Func {
-                    principal: id,
-                    method: "http_request_streaming_callback".to_string(),
-                },
-            })
-        }
+/// `206 Partial Content` with the bytes `start..=end` of `variant`, which only paths
+/// left to the uncertified fallback serve. Longer ranges are truncated to
+/// `max_body_size`, `Content-Range` tells where to resume.
+fn partial_response(
+    mut headers: Vec<HeaderField>,
+    variant: &StableEncoding,
+    start: u64,
+    end: u64,
+    max_body_size: u64,
+    path: &str,
+) -> HttpResponse {
+    let end = end.min(start + max_body_size - 1);
+    headers.push(HeaderField(
+        "Content-Range".to_string(),
+        format!("bytes {start}-{end}/{}", variant.total_length),
+    ));
+    headers.push(HeaderField(
+        "Content-Length".to_string(),
+        (end - start + 1).to_string(),
+    ));
+    headers.extend(fallback_certificate_headers(path));
+    HttpResponse {
+        body: variant.read_range(start, end),
+        status_code: 206,
+        headers,
+        streaming_strategy: None,
     }
 }
 
-fn create_token(arg: CreateStrategyArgs) -> Option<StreamingCallbackToken> {
-    let v = arg.chunk_index.clone() + 1;
-    if v >= arg.chunk_size {
-        return None;
+/// Reads at most `max_body_size` bytes from `offset` on, wherever the chunks the content
+/// was uploaded in begin and end. Also returns the offset to continue from, if any.
+fn read_window(
+    variant: &StableEncoding,
+    offset: u64,
+    max_body_size: u64,
+) -> (Vec<u8>, Option<u64>) {
+    if offset >= variant.total_length {
+        return (vec![], None);
+    }
+    let end = offset
+        .saturating_add(max_body_size)
+        .min(variant.total_length);
+    let next = (end < variant.total_length).then_some(end);
+    (variant.read_range(offset, end - 1), next)
+}
+
+fn create_strategy(arg: CreateStrategyArgs) -> StreamingStrategy {
+    StreamingStrategy::Callback {
+        token: StreamingCallbackToken {
+            asset_id: arg.asset_id,
+            offset: arg.offset,
+            content_encoding: arg.content_encoding.token().to_string(),
+            signature: arg.signature,
+        },
+        callback: Func {
+            principal: ic_cdk::id(),
+            method: "http_request_streaming_callback".to_string(),
+        },
     }
-    Some(StreamingCallbackToken {
-        asset_id: arg.asset_id,
-        chunk_index: arg.chunk_index.clone() + 1,
-        content_encoding: "gzip".to_string(),
-        chunk_size: arg.chunk_size,
-    })
 }
 
+/// The gateway protocol fixes this signature, so a stale token (for instance one for
+/// an asset deleted mid-download) ends the stream with an empty body instead of trapping.
 #[query]
 #[candid_method(query)]
 pub fn http_request_streaming_callback(
@@ -83,20 +445,189 @@ pub fn http_request_streaming_callback(
 ) -> StreamingCallbackHttpResponse {
     STATE.with(|state| {
         let state = state.borrow();
-        match state.assets.get(&token_arg.asset_id) {
-            None => panic!("asset id not found"),
-            Some(asset) => {
-                let arg = CreateStrategyArgs {
-                    asset_id: token_arg.asset_id.clone(),
-                    chunk_index: token_arg.chunk_index,
-                    chunk_size: token_arg.chunk_size,
-                };
-                let token = create_token(arg);
-                StreamingCallbackHttpResponse {
-                    token,
-                    body: asset.content.get(&token_arg.chunk_index).unwrap().iter().map(|b| b.clone()).collect(),
-                }
-            }
-        }
+        let Some(asset) = state
+            .assets
+            .get(&token_arg.asset_id)
+            .filter(|asset| is_authorized(&state, asset, token_arg.signature.as_ref()))
+        else {
+            return StreamingCallbackHttpResponse {
+                body: vec![],
+                token: None,
+            };
+        };
+        let Some((encoding, variant)) = ContentEncoding::from_token(&token_arg.content_encoding)
+            .and_then(|encoding| Some((encoding, asset.encodings.get(&encoding)?)))
+        else {
+            return StreamingCallbackHttpResponse {
+                body: vec![],
+                token: None,
+            };
+        };
+        let (body, next) = read_window(&variant, token_arg.offset, state.config.max_body_size);
+        let token = next.map(|offset| StreamingCallbackToken {
+            asset_id: token_arg.asset_id,
+            offset,
+            content_encoding: encoding.token().to_string(),
+            signature: token_arg.signature.clone(),
+        });
+        StreamingCallbackHttpResponse { token, body }
     })
 }
+
+/// Sets the largest body, in bytes, of HTTP responses and streaming callbacks. Longer
+/// content is streamed in windows of this size.
+#[update(guard = "is_admin")]
+#[candid_method(update)]
+pub fn set_max_body_size(max_body_size: u64) -> Result<(), StorageError> {
+    if !(MIN_BODY_SIZE..=MAX_BODY_SIZE).contains(&max_body_size) {
+        return Err(StorageError::InvalidBodySize {
+            min: MIN_BODY_SIZE,
+            max: MAX_BODY_SIZE,
+        });
+    }
+    STATE.with(|state| state.borrow_mut().config.max_body_size = max_body_size);
+    Ok(())
+}
+
+#[query(guard = "is_reader")]
+#[candid_method(query)]
+pub fn get_max_body_size() -> u64 {
+    STATE.with(|state| state.borrow().config.max_body_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{certification::init_certification, types::test_asset};
+    use ic_stable_memory::{
+        collections::{SHashMap, SVec},
+        stable_memory_init,
+    };
+
+    fn variant(chunks: &[&[u8]]) -> StableEncoding {
+        let mut content = SHashMap::new();
+        for (index, bytes) in chunks.iter().enumerate() {
+            let mut chunk = SVec::new();
+            bytes.iter().for_each(|b| chunk.push(*b).unwrap());
+            content.insert(index as u32, chunk).unwrap();
+        }
+        StableEncoding {
+            content,
+            chunk_count: chunks.len() as u32,
+            total_length: chunks.iter().map(|bytes| bytes.len() as u64).sum(),
+            sha256: [0; 32],
+        }
+    }
+
+    #[test]
+    fn reads_windows_across_chunks() {
+        stable_memory_init();
+        let variant = variant(&[b"abc", b"d", b"efghij"]);
+
+        assert_eq!(read_window(&variant, 0, 4), (b"abcd".to_vec(), Some(4)));
+        assert_eq!(read_window(&variant, 4, 4), (b"efgh".to_vec(), Some(8)));
+        assert_eq!(read_window(&variant, 8, 4), (b"ij".to_vec(), None));
+        assert_eq!(read_window(&variant, 0, 10), (b"abcdefghij".to_vec(), None));
+        assert_eq!(read_window(&variant, 10, 4), (vec![], None));
+    }
+
+    #[test]
+    fn leaves_partial_responses_to_the_fallback() {
+        stable_memory_init();
+        init_certification(&SHashMap::new());
+        let variant = variant(&[b"abc", b"d", b"efghij"]);
+        let path = "/asset/1/file.txt";
+
+        let response = partial_response(vec![], &variant, 2, 9, 4, path);
+        assert_eq!(response.status_code, 206);
+        assert_eq!(response.body, b"cdef");
+        assert_eq!(
+            find_header(&response.headers, "content-range"),
+            Some("bytes 2-5/10")
+        );
+        let fallback = fallback_certificate_headers(path);
+        assert_eq!(
+            find_header(&response.headers, "ic-certificateexpression"),
+            find_header(&fallback, "ic-certificateexpression")
+        );
+    }
+
+    #[test]
+    fn certifies_not_acceptable_on_public_asset_paths() {
+        stable_memory_init();
+        init_certification(&SHashMap::new());
+
+        let response = not_acceptable_response(1);
+        assert_eq!(response.status_code, 406);
+        let fallback = fallback_certificate_headers("/asset/1");
+        let expression = find_header(&response.headers, "ic-certificateexpression");
+        assert!(expression.is_some());
+        assert_ne!(
+            expression,
+            find_header(&fallback, "ic-certificateexpression")
+        );
+    }
+
+    #[test]
+    fn certifies_options_on_public_asset_paths() {
+        stable_memory_init();
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            state.assets.insert(1, test_asset(1, b"hello")).unwrap();
+            let mut private = test_asset(2, b"hello");
+            private.visibility = Visibility::Private;
+            state.assets.insert(2, private).unwrap();
+        });
+
+        assert_eq!(certified_asset("/asset/1"), Some(1));
+        assert_eq!(certified_asset("/asset/1/file.txt"), None);
+        assert_eq!(certified_asset("/asset/2"), None);
+        assert_eq!(certified_asset("/asset/3"), None);
+        assert_eq!(certified_asset("/by-name/x"), None);
+    }
+
+    #[test]
+    fn leaves_room_for_headers() {
+        stable_memory_init();
+        assert!(set_max_body_size(2 * 1024 * 1024).is_err());
+        assert!(set_max_body_size(MIN_BODY_SIZE - 1).is_err());
+        assert_eq!(set_max_body_size(DEFAULT_MAX_BODY_SIZE), Ok(()));
+    }
+
+    #[test]
+    fn matches_entity_tags() {
+        stable_memory_init();
+        let mut asset = test_asset(1, b"hello");
+        asset.updated_at = 1_700_000_000_000_000_000;
+        let variant = asset.encodings.get(&ContentEncoding::Identity).unwrap();
+        let etag = variant.etag();
+        let not_modified = |name: &str, value: &str| {
+            let headers = [HeaderField(name.to_string(), value.to_string())];
+            is_not_modified(&headers, &asset, &variant)
+        };
+
+        assert!(not_modified("If-None-Match", &etag));
+        assert!(not_modified("if-none-match", &format!("W/{etag}")));
+        assert!(not_modified("If-None-Match", &format!("\"other\", {etag}")));
+        assert!(not_modified(
+            "If-None-Match",
+            &format!("W/\"other\",W/{etag}")
+        ));
+        assert!(not_modified("If-None-Match", "*"));
+        assert!(!not_modified("If-None-Match", "\"other\", W/\"another\""));
+        assert!(!not_modified("If-None-Match", ""));
+
+        let since = "Tue, 14 Nov 2023 22:13:20 GMT";
+        assert!(not_modified("If-Modified-Since", since));
+        assert!(!not_modified(
+            "If-Modified-Since",
+            "Tue, 14 Nov 2023 22:13:19 GMT"
+        ));
+        // `If-None-Match` wins when both are sent
+        let headers = [
+            HeaderField("If-None-Match".to_string(), "\"other\"".to_string()),
+            HeaderField("If-Modified-Since".to_string(), since.to_string()),
+        ];
+        assert!(!is_not_modified(&headers, &asset, &variant));
+    }
+}
@@ -1,7 +1,18 @@
+pub mod access_control;
+pub mod aliases;
 pub mod asset_handler;
+pub mod certification;
 pub mod chunk_handler;
+pub mod compression;
+pub mod cors;
 pub mod http_handler;
 pub mod memory;
+pub mod migrations;
+pub mod quota;
+pub mod router;
+pub mod signed_url;
 pub mod types;
+pub mod upload_handler;
+pub mod urls;
 pub mod utils;
 pub mod candid_file_generator;
\ No newline at end of file
@@ -1,10 +1,25 @@
 use std::cell::RefCell;
 
 use candid::{candid_method, Nat};
-use ic_cdk::{init, update, pre_upgrade};
-use ic_stable_memory::{stable_memory_init, stable_memory_pre_upgrade};
+use ic_cdk::{init, post_upgrade, pre_upgrade, update};
+use ic_stable_memory::{
+    retrieve_custom_data, stable, stable_memory_init, stable_memory_post_upgrade,
+    stable_memory_pre_upgrade, store_custom_data, SBox,
+};
 
-use crate::types::State;
+use crate::{
+    access_control::is_admin,
+    certification::init_certification,
+    chunk_handler::schedule_cleanup,
+    compression::{abort_compression, schedule_compression},
+    migrations::{migrate, SCHEMA_VERSION, UNVERSIONED},
+    types::{Role, State, StorageError},
+};
+
+/// Custom data slot the boxed `State` is kept in between upgrades.
+const STATE_KEY: usize = 0;
+/// Custom data slot holding the schema version the boxed `State` was written with.
+const VERSION_KEY: usize = 1;
 
 thread_local! {
     pub static STATE: RefCell<State> = RefCell::default();
@@ -14,26 +29,133 @@ thread_local! {
 #[candid_method(init)]
 pub fn init() {
     stable_memory_init();
+    init_state();
+    schedule_cleanup();
+}
+
+/// Sets up an empty `State`, administered by the caller.
+fn init_state() {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state
+            .roles
+            .insert(ic_cdk::caller(), Role::Admin)
+            .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+        init_certification(&state.assets);
+    });
+}
+
+#[pre_upgrade]
+pub fn pre_upgrade() {
+    save_state();
+    stable_memory_pre_upgrade().expect("failed to pre upgrade");
+}
+
+/// Upgrading the baseline build starts over with an empty `State`: it kept its state
+/// on the heap and never saved the allocator, so the assets it stored are lost either
+/// way, and are overwritten.
+#[post_upgrade]
+pub fn post_upgrade() {
+    if !allocator_saved() {
+        stable_memory_init();
+        init_state();
+        schedule_cleanup();
+        return;
+    }
+    stable_memory_post_upgrade();
+    restore_state();
+    STATE.with(|state| {
+        let state = state.borrow();
+        init_certification(&state.assets);
+        if !state.pending_encodings.is_empty() {
+            schedule_compression();
+        }
+    });
+    schedule_cleanup();
+}
+
+/// Whether `stable_memory_pre_upgrade` ran before the upgrade, leaving a pointer to
+/// the saved allocator at the start of stable memory.
+fn allocator_saved() -> bool {
+    if stable::size_pages() == 0 {
+        return false;
+    }
+    let mut pointer = [0; 8];
+    stable::read(0, &mut pointer);
+    pointer != [0; 8]
 }
 
-#[update]
+/// Moves the `State` root out of the heap into a stable box.
+fn save_state() {
+    abort_compression();
+    let state = STATE.with(|state| state.take());
+    let boxed = SBox::new(state).unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    store_custom_data(STATE_KEY, boxed);
+    let version =
+        SBox::new(SCHEMA_VERSION).unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    store_custom_data(VERSION_KEY, version);
+}
+
+/// Puts the `State` root saved by `save_state` back into the heap, migrating it first
+/// if it was written by an older schema version.
+fn restore_state() {
+    let version = retrieve_custom_data::<u32>(VERSION_KEY).map_or(UNVERSIONED, SBox::into_inner);
+    migrate(STATE_KEY, version);
+    let state = retrieve_custom_data::<State>(STATE_KEY)
+        .expect("state not found in stable memory")
+        .into_inner();
+    STATE.with(|s| *s.borrow_mut() = state);
+}
+
+#[update(guard = "is_admin")]
 #[candid_method(update)]
-pub async fn is_full() -> bool{
-    let arg = ic_cdk::api::management_canister::main::CanisterIdRecord{
-        canister_id: ic_cdk::id()
+pub async fn is_full() -> Result<bool, StorageError> {
+    let arg = ic_cdk::api::management_canister::main::CanisterIdRecord {
+        canister_id: ic_cdk::id(),
     };
-    let (info,) = ic_cdk::api::management_canister::main::canister_status(arg).await.unwrap();
-    ic_cdk::println!("{:?}", info);
+    let (info,) = ic_cdk::api::management_canister::main::canister_status(arg)
+        .await
+        .map_err(|(code, message)| {
+            StorageError::CanisterStatusFailed(format!("{code:?}: {message}"))
+        })?;
     let fourty_gb: u64 = 40 * 1024 * 1024 * 1024;
     let max_size = Nat::from(fourty_gb);
-    if info.memory_size >= max_size{
-        true
-    }else{
-        false
-    }
+    Ok(info.memory_size >= max_size)
 }
 
-// #[pre_upgrade]
-// pub fn pre_upgrade(){
-//     stable_memory_pre_upgrade().expect("failed to pre upgrade");
-// }
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{test_asset, ContentEncoding};
+
+    #[test]
+    fn assets_survive_upgrade() {
+        stable_memory_init();
+        assert!(!allocator_saved());
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            state.get_chunk_id();
+            let id = state.get_asset_id();
+            state.assets.insert(id, test_asset(id, b"hello")).unwrap();
+        });
+
+        save_state();
+        stable_memory_pre_upgrade().unwrap();
+        assert!(allocator_saved());
+        STATE.with(|state| assert!(state.borrow().assets.is_empty()));
+
+        stable_memory_post_upgrade();
+        restore_state();
+
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            assert_eq!(state.get_chunk_id(), 2);
+            assert_eq!(state.get_asset_id(), 2);
+            let asset = state.assets.get(&1).expect("asset lost during upgrade");
+            assert_eq!(*asset.file_name, "file.txt");
+            let encoding = asset.encodings.get(&ContentEncoding::Identity).unwrap();
+            assert_eq!(encoding.sha256, [7; 32]);
+            assert_eq!(encoding.read_range(0, 4), b"hello");
+        });
+    }
+}
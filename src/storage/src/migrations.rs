@@ -0,0 +1,1704 @@
+use std::collections::HashMap;
+
+use candid::Principal;
+use ic_stable_memory::{
+    collections::{SHashMap, SVec},
+    retrieve_custom_data, store_custom_data, SBox,
+};
+use sha2::{Digest, Sha256};
+
+use crate::types::{
+    name_key, Config, ContentEncoding, Disposition, Quota, Role, StableAsset, StableChunk,
+    StableCors, StableEncoding, StableSha256, StableString, StableUpload, StableUrlConfig, State,
+    Usage, Visibility, DEFAULT_MAX_BODY_SIZE, DEFAULT_UPLOAD_TTL,
+};
+
+/// Layout version of `State` (and everything stored inside it) written by this build.
+///
+/// Bump it whenever a stable struct changes shape or stored data has to be brought up
+/// to date, and append the step converting the previous version to `STEPS`.
+pub const SCHEMA_VERSION: u32 = 22;
+
+/// Version assumed for states saved before the version was recorded. The baseline build
+/// saved none at all, see `post_upgrade`.
+pub const UNVERSIONED: u32 = 1;
+
+/// `STEPS[n]` converts the state boxed under the given custom data key from schema
+/// version `n + 1` to `n + 2`, leaving the result boxed under the same key.
+const STEPS: [fn(usize); (SCHEMA_VERSION - 1) as usize] = [
+    v1_to_v2, v2_to_v3, v3_to_v4, v4_to_v5, v5_to_v6, v6_to_v7, v7_to_v8, v8_to_v9, v9_to_v10,
+    v10_to_v11, v11_to_v12, v12_to_v13, v13_to_v14, v14_to_v15, v15_to_v16, v16_to_v17, v17_to_v18,
+    v18_to_v19, v19_to_v20, v20_to_v21, v21_to_v22,
+];
+
+/// Brings the state boxed under `key` from `version` up to [`SCHEMA_VERSION`], one
+/// step at a time.
+pub(crate) fn migrate(key: usize, version: u32) {
+    run_steps(key, version, &STEPS);
+}
+
+fn run_steps(key: usize, version: u32, steps: &[fn(usize)]) {
+    let target = steps.len() as u32 + 1;
+    if version > target {
+        ic_cdk::trap(&format!(
+            "Stored schema version {version} is newer than {target}, downgrades are not supported"
+        ));
+    }
+    for step in &steps[(version - 1) as usize..] {
+        step(key);
+    }
+}
+
+/// Layouts as they were before upload sessions were introduced.
+mod v1 {
+    use candid::Principal;
+    use ic_stable_memory::{
+        collections::{SHashMap, SVec},
+        derive::{AsFixedSizeBytes, StableType},
+    };
+
+    use crate::types::{ContentEncoding, StableString};
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct StableAsset {
+        pub content: SHashMap<u32, SVec<u8>>,
+        pub file_name: StableString,
+        pub owner: Principal,
+        pub content_encoding: ContentEncoding,
+        pub url: StableString,
+        pub chunk_size: u32,
+        pub id: u128,
+        pub content_type: StableString,
+        pub sha256: [u8; 32],
+    }
+
+    impl StableAsset {
+        pub fn total_length(&self) -> u64 {
+            (0..self.chunk_size)
+                .filter_map(|index| self.content.get(&index).map(|chunk| chunk.len() as u64))
+                .sum()
+        }
+    }
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct StableChunk {
+        pub content: SVec<u8>,
+        pub owner: Principal,
+        pub created_at: u64,
+        pub order: u32,
+        pub checksum: u32,
+        pub id: u128,
+    }
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct State {
+        pub chunk_count: u128,
+        pub chunks: SHashMap<u128, StableChunk>,
+        pub asset_count: u128,
+        pub assets: SHashMap<u128, StableAsset>,
+    }
+}
+
+/// Adds upload sessions. Pending chunks are not tied to any session and could never be
+/// committed anymore, so they are dropped.
+fn v1_to_v2(key: usize) {
+    let old = retrieve_custom_data::<v1::State>(key)
+        .expect("state not found in stable memory")
+        .into_inner();
+    let new = v2::State {
+        chunk_count: old.chunk_count,
+        chunks: SHashMap::new(),
+        asset_count: old.asset_count,
+        assets: old.assets,
+        upload_count: 1,
+        uploads: SHashMap::new(),
+    };
+    let boxed = SBox::new(new).unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    store_custom_data(key, boxed);
+}
+
+/// Layouts as they were before the runtime configuration was added.
+mod v2 {
+    use candid::Principal;
+    use ic_stable_memory::{
+        collections::{SHashMap, SVec},
+        derive::{AsFixedSizeBytes, StableType},
+    };
+
+    use super::v1::StableAsset;
+    use crate::types::{ContentEncoding, StableString};
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct StableUpload {
+        pub id: u128,
+        pub owner: Principal,
+        pub file_name: StableString,
+        pub content_type: StableString,
+        pub content_encoding: ContentEncoding,
+        pub total_size: u64,
+        pub chunks: SHashMap<u32, u128>,
+        pub created_at: u64,
+        pub updated_at: u64,
+    }
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct StableChunk {
+        pub content: SVec<u8>,
+        pub owner: Principal,
+        pub created_at: u64,
+        pub upload_id: u128,
+        pub index: u32,
+        pub checksum: u32,
+        pub id: u128,
+    }
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct State {
+        pub chunk_count: u128,
+        pub chunks: SHashMap<u128, StableChunk>,
+        pub asset_count: u128,
+        pub assets: SHashMap<u128, StableAsset>,
+        pub upload_count: u128,
+        pub uploads: SHashMap<u128, StableUpload>,
+    }
+}
+
+/// Adds the runtime configuration, starting from the defaults.
+fn v2_to_v3(key: usize) {
+    let old = retrieve_custom_data::<v2::State>(key)
+        .expect("state not found in stable memory")
+        .into_inner();
+    let new = v3::State {
+        chunk_count: old.chunk_count,
+        chunks: old.chunks,
+        asset_count: old.asset_count,
+        assets: old.assets,
+        upload_count: old.upload_count,
+        uploads: old.uploads,
+        config: v3::Config {
+            upload_ttl: DEFAULT_UPLOAD_TTL,
+        },
+    };
+    let boxed = SBox::new(new).unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    store_custom_data(key, boxed);
+}
+
+/// Layouts as they were before storage quotas were added.
+mod v3 {
+    use ic_stable_memory::{
+        collections::SHashMap,
+        derive::{AsFixedSizeBytes, StableType},
+    };
+
+    use super::{
+        v1::StableAsset,
+        v2::{StableChunk, StableUpload},
+    };
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct Config {
+        pub upload_ttl: u64,
+    }
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct State {
+        pub chunk_count: u128,
+        pub chunks: SHashMap<u128, StableChunk>,
+        pub asset_count: u128,
+        pub assets: SHashMap<u128, StableAsset>,
+        pub upload_count: u128,
+        pub uploads: SHashMap<u128, StableUpload>,
+        pub config: Config,
+    }
+}
+
+/// Adds storage quotas, charging every owner for the assets and pending chunks it
+/// already holds.
+fn v3_to_v4(key: usize) {
+    let old = retrieve_custom_data::<v3::State>(key)
+        .expect("state not found in stable memory")
+        .into_inner();
+    let mut usage: HashMap<Principal, Usage> = HashMap::new();
+    for (_, asset) in old.assets.iter() {
+        let entry = usage.entry(asset.owner).or_default();
+        entry.bytes += asset.total_length();
+        entry.assets += 1;
+    }
+    for (_, chunk) in old.chunks.iter() {
+        usage.entry(chunk.owner).or_default().bytes += chunk.content.len() as u64;
+    }
+
+    let mut new = v4::State {
+        chunk_count: old.chunk_count,
+        chunks: old.chunks,
+        asset_count: old.asset_count,
+        assets: old.assets,
+        upload_count: old.upload_count,
+        uploads: old.uploads,
+        config: v4::Config {
+            upload_ttl: old.config.upload_ttl,
+            default_quota: Quota::default(),
+        },
+        usage: SHashMap::new(),
+        quotas: SHashMap::new(),
+    };
+    for (principal, usage) in usage {
+        new.usage
+            .insert(principal, usage)
+            .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    }
+    let boxed = SBox::new(new).unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    store_custom_data(key, boxed);
+}
+
+/// Layouts as they were before roles were added.
+mod v4 {
+    use candid::Principal;
+    use ic_stable_memory::{
+        collections::SHashMap,
+        derive::{AsFixedSizeBytes, StableType},
+    };
+
+    use super::{
+        v1::StableAsset,
+        v2::{StableChunk, StableUpload},
+    };
+    use crate::types::{Quota, Usage};
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct Config {
+        pub upload_ttl: u64,
+        pub default_quota: Quota,
+    }
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct State {
+        pub chunk_count: u128,
+        pub chunks: SHashMap<u128, StableChunk>,
+        pub asset_count: u128,
+        pub assets: SHashMap<u128, StableAsset>,
+        pub upload_count: u128,
+        pub uploads: SHashMap<u128, StableUpload>,
+        pub config: Config,
+        pub usage: SHashMap<Principal, Usage>,
+        pub quotas: SHashMap<Principal, Quota>,
+    }
+}
+
+/// Adds roles. Everyone keeps being allowed to read, but uploads now take a role that
+/// controllers grant from there.
+fn v4_to_v5(key: usize) {
+    let old = retrieve_custom_data::<v4::State>(key)
+        .expect("state not found in stable memory")
+        .into_inner();
+    let new = v5::State {
+        chunk_count: old.chunk_count,
+        chunks: old.chunks,
+        asset_count: old.asset_count,
+        assets: old.assets,
+        upload_count: old.upload_count,
+        uploads: old.uploads,
+        config: v5::Config {
+            upload_ttl: old.config.upload_ttl,
+            default_quota: old.config.default_quota,
+            default_role: Some(Role::Reader),
+        },
+        usage: old.usage,
+        quotas: old.quotas,
+        roles: SHashMap::new(),
+    };
+    let boxed = SBox::new(new).unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    store_custom_data(key, boxed);
+}
+
+/// Layouts as they were before assets had a visibility.
+mod v5 {
+    use candid::Principal;
+    use ic_stable_memory::{
+        collections::SHashMap,
+        derive::{AsFixedSizeBytes, StableType},
+    };
+
+    use super::{
+        v1::StableAsset,
+        v2::{StableChunk, StableUpload},
+    };
+    use crate::types::{Quota, Role, Usage};
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct Config {
+        pub upload_ttl: u64,
+        pub default_quota: Quota,
+        pub default_role: Option<Role>,
+    }
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct State {
+        pub chunk_count: u128,
+        pub chunks: SHashMap<u128, StableChunk>,
+        pub asset_count: u128,
+        pub assets: SHashMap<u128, StableAsset>,
+        pub upload_count: u128,
+        pub uploads: SHashMap<u128, StableUpload>,
+        pub config: Config,
+        pub usage: SHashMap<Principal, Usage>,
+        pub quotas: SHashMap<Principal, Quota>,
+        pub roles: SHashMap<Principal, Role>,
+    }
+}
+
+/// Adds visibility and sharing to assets. Every asset was served publicly so far, so
+/// they all start out `Public`.
+fn v5_to_v6(key: usize) {
+    let mut old = retrieve_custom_data::<v5::State>(key)
+        .expect("state not found in stable memory")
+        .into_inner();
+    let mut assets = SHashMap::new_with_capacity(old.assets.len())
+        .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    let ids: Vec<u128> = old.assets.iter().map(|(id, _)| *id).collect();
+    for id in ids {
+        let asset = old.assets.remove(&id).unwrap();
+        let asset = v6::StableAsset {
+            content: asset.content,
+            file_name: asset.file_name,
+            owner: asset.owner,
+            content_encoding: asset.content_encoding,
+            url: asset.url,
+            chunk_size: asset.chunk_size,
+            id: asset.id,
+            content_type: asset.content_type,
+            sha256: asset.sha256,
+            visibility: Visibility::Public,
+            acl: SHashMap::new(),
+        };
+        assets
+            .insert(id, asset)
+            .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    }
+
+    let new = v6::State {
+        chunk_count: old.chunk_count,
+        chunks: old.chunks,
+        asset_count: old.asset_count,
+        assets,
+        upload_count: old.upload_count,
+        uploads: old.uploads,
+        config: old.config,
+        usage: old.usage,
+        quotas: old.quotas,
+        roles: old.roles,
+    };
+    let boxed = SBox::new(new).unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    store_custom_data(key, boxed);
+}
+
+/// Layouts as they were before signed URLs were added.
+mod v6 {
+    use candid::Principal;
+    use ic_stable_memory::{
+        collections::{SHashMap, SVec},
+        derive::{AsFixedSizeBytes, StableType},
+    };
+
+    use super::{
+        v2::{StableChunk, StableUpload},
+        v5::Config,
+    };
+    use crate::types::{ContentEncoding, Permission, Quota, Role, StableString, Usage, Visibility};
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct StableAsset {
+        pub content: SHashMap<u32, SVec<u8>>,
+        pub file_name: StableString,
+        pub owner: Principal,
+        pub content_encoding: ContentEncoding,
+        pub url: StableString,
+        pub chunk_size: u32,
+        pub id: u128,
+        pub content_type: StableString,
+        pub sha256: [u8; 32],
+        pub visibility: Visibility,
+        pub acl: SHashMap<Principal, Permission>,
+    }
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct State {
+        pub chunk_count: u128,
+        pub chunks: SHashMap<u128, StableChunk>,
+        pub asset_count: u128,
+        pub assets: SHashMap<u128, StableAsset>,
+        pub upload_count: u128,
+        pub uploads: SHashMap<u128, StableUpload>,
+        pub config: Config,
+        pub usage: SHashMap<Principal, Usage>,
+        pub quotas: SHashMap<Principal, Quota>,
+        pub roles: SHashMap<Principal, Role>,
+    }
+}
+
+/// Adds the key version of signed URLs to assets, and the slot of their secret.
+fn v6_to_v7(key: usize) {
+    let mut old = retrieve_custom_data::<v6::State>(key)
+        .expect("state not found in stable memory")
+        .into_inner();
+    let mut assets = SHashMap::new_with_capacity(old.assets.len())
+        .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    let ids: Vec<u128> = old.assets.iter().map(|(id, _)| *id).collect();
+    for id in ids {
+        let asset = old.assets.remove(&id).unwrap();
+        let asset = v7::StableAsset {
+            content: asset.content,
+            file_name: asset.file_name,
+            owner: asset.owner,
+            content_encoding: asset.content_encoding,
+            url: asset.url,
+            chunk_size: asset.chunk_size,
+            id: asset.id,
+            content_type: asset.content_type,
+            sha256: asset.sha256,
+            visibility: asset.visibility,
+            acl: asset.acl,
+            url_key_version: 0,
+        };
+        assets
+            .insert(id, asset)
+            .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    }
+
+    let new = v7::State {
+        chunk_count: old.chunk_count,
+        chunks: old.chunks,
+        asset_count: old.asset_count,
+        assets,
+        upload_count: old.upload_count,
+        uploads: old.uploads,
+        config: old.config,
+        usage: old.usage,
+        quotas: old.quotas,
+        roles: old.roles,
+        url_secret: None,
+    };
+    let boxed = SBox::new(new).unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    store_custom_data(key, boxed);
+}
+
+/// Layouts as they were before assets recorded their creation time.
+mod v7 {
+    use candid::Principal;
+    use ic_stable_memory::{
+        collections::{SHashMap, SVec},
+        derive::{AsFixedSizeBytes, StableType},
+    };
+
+    use super::{
+        v2::{StableChunk, StableUpload},
+        v5::Config,
+    };
+    use crate::types::{ContentEncoding, Permission, Quota, Role, StableString, Usage, Visibility};
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct StableAsset {
+        pub content: SHashMap<u32, SVec<u8>>,
+        pub file_name: StableString,
+        pub owner: Principal,
+        pub content_encoding: ContentEncoding,
+        pub url: StableString,
+        pub chunk_size: u32,
+        pub id: u128,
+        pub content_type: StableString,
+        pub sha256: [u8; 32],
+        pub visibility: Visibility,
+        pub acl: SHashMap<Principal, Permission>,
+        pub url_key_version: u32,
+    }
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct State {
+        pub chunk_count: u128,
+        pub chunks: SHashMap<u128, StableChunk>,
+        pub asset_count: u128,
+        pub assets: SHashMap<u128, StableAsset>,
+        pub upload_count: u128,
+        pub uploads: SHashMap<u128, StableUpload>,
+        pub config: Config,
+        pub usage: SHashMap<Principal, Usage>,
+        pub quotas: SHashMap<Principal, Quota>,
+        pub roles: SHashMap<Principal, Role>,
+        pub url_secret: Option<[u8; 32]>,
+    }
+}
+
+/// Adds the creation time of assets. It was never recorded, so existing assets get `0`.
+fn v7_to_v8(key: usize) {
+    let mut old = retrieve_custom_data::<v7::State>(key)
+        .expect("state not found in stable memory")
+        .into_inner();
+    let mut assets = SHashMap::new_with_capacity(old.assets.len())
+        .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    let ids: Vec<u128> = old.assets.iter().map(|(id, _)| *id).collect();
+    for id in ids {
+        let asset = old.assets.remove(&id).unwrap();
+        let asset = v8::StableAsset {
+            content: asset.content,
+            file_name: asset.file_name,
+            owner: asset.owner,
+            content_encoding: asset.content_encoding,
+            url: asset.url,
+            chunk_size: asset.chunk_size,
+            id: asset.id,
+            content_type: asset.content_type,
+            sha256: asset.sha256,
+            visibility: asset.visibility,
+            acl: asset.acl,
+            url_key_version: asset.url_key_version,
+            created_at: 0,
+        };
+        assets
+            .insert(id, asset)
+            .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    }
+
+    let new = v8::State {
+        chunk_count: old.chunk_count,
+        chunks: old.chunks,
+        asset_count: old.asset_count,
+        assets,
+        upload_count: old.upload_count,
+        uploads: old.uploads,
+        config: old.config,
+        usage: old.usage,
+        quotas: old.quotas,
+        roles: old.roles,
+        url_secret: old.url_secret,
+    };
+    let boxed = SBox::new(new).unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    store_custom_data(key, boxed);
+}
+
+/// Layouts as they were before assets carried their length, update time and description.
+mod v8 {
+    use candid::Principal;
+    use ic_stable_memory::{
+        collections::{SHashMap, SVec},
+        derive::{AsFixedSizeBytes, StableType},
+    };
+
+    use super::{
+        v2::{StableChunk, StableUpload},
+        v5::Config,
+    };
+    use crate::types::{ContentEncoding, Permission, Quota, Role, StableString, Usage, Visibility};
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct StableAsset {
+        pub content: SHashMap<u32, SVec<u8>>,
+        pub file_name: StableString,
+        pub owner: Principal,
+        pub content_encoding: ContentEncoding,
+        pub url: StableString,
+        pub chunk_size: u32,
+        pub id: u128,
+        pub content_type: StableString,
+        pub sha256: [u8; 32],
+        pub visibility: Visibility,
+        pub acl: SHashMap<Principal, Permission>,
+        pub url_key_version: u32,
+        pub created_at: u64,
+    }
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct State {
+        pub chunk_count: u128,
+        pub chunks: SHashMap<u128, StableChunk>,
+        pub asset_count: u128,
+        pub assets: SHashMap<u128, StableAsset>,
+        pub upload_count: u128,
+        pub uploads: SHashMap<u128, StableUpload>,
+        pub config: Config,
+        pub usage: SHashMap<Principal, Usage>,
+        pub quotas: SHashMap<Principal, Quota>,
+        pub roles: SHashMap<Principal, Role>,
+        pub url_secret: Option<[u8; 32]>,
+    }
+}
+
+/// Stores the content length of assets, which was summed over the chunks on every
+/// request so far, along with an update time and an empty description.
+fn v8_to_v9(key: usize) {
+    let mut old = retrieve_custom_data::<v8::State>(key)
+        .expect("state not found in stable memory")
+        .into_inner();
+    let mut assets = SHashMap::new_with_capacity(old.assets.len())
+        .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    let ids: Vec<u128> = old.assets.iter().map(|(id, _)| *id).collect();
+    for id in ids {
+        let asset = old.assets.remove(&id).unwrap();
+        let total_length = (0..asset.chunk_size)
+            .filter_map(|index| asset.content.get(&index).map(|chunk| chunk.len() as u64))
+            .sum();
+        let asset = v9::StableAsset {
+            content: asset.content,
+            file_name: asset.file_name,
+            owner: asset.owner,
+            content_encoding: asset.content_encoding,
+            url: asset.url,
+            chunk_size: asset.chunk_size,
+            id: asset.id,
+            content_type: asset.content_type,
+            sha256: asset.sha256,
+            visibility: asset.visibility,
+            acl: asset.acl,
+            url_key_version: asset.url_key_version,
+            created_at: asset.created_at,
+            updated_at: asset.created_at,
+            total_length,
+            description: None,
+        };
+        assets
+            .insert(id, asset)
+            .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    }
+
+    let new = v9::State {
+        chunk_count: old.chunk_count,
+        chunks: old.chunks,
+        asset_count: old.asset_count,
+        assets,
+        upload_count: old.upload_count,
+        uploads: old.uploads,
+        config: old.config,
+        usage: old.usage,
+        quotas: old.quotas,
+        roles: old.roles,
+        url_secret: old.url_secret,
+    };
+    let boxed = SBox::new(new).unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    store_custom_data(key, boxed);
+}
+
+/// Layouts as they were before chunks were hashed with SHA-256.
+mod v9 {
+    use candid::Principal;
+    use ic_stable_memory::{
+        collections::{SHashMap, SVec},
+        derive::{AsFixedSizeBytes, StableType},
+    };
+
+    use super::{
+        v2::{StableChunk, StableUpload},
+        v5::Config,
+    };
+    use crate::types::{ContentEncoding, Permission, Quota, Role, StableString, Usage, Visibility};
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct StableAsset {
+        pub content: SHashMap<u32, SVec<u8>>,
+        pub file_name: StableString,
+        pub owner: Principal,
+        pub content_encoding: ContentEncoding,
+        pub url: StableString,
+        pub chunk_size: u32,
+        pub id: u128,
+        pub content_type: StableString,
+        pub sha256: [u8; 32],
+        pub visibility: Visibility,
+        pub acl: SHashMap<Principal, Permission>,
+        pub url_key_version: u32,
+        pub created_at: u64,
+        pub updated_at: u64,
+        pub total_length: u64,
+        pub description: Option<StableString>,
+    }
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct State {
+        pub chunk_count: u128,
+        pub chunks: SHashMap<u128, StableChunk>,
+        pub asset_count: u128,
+        pub assets: SHashMap<u128, StableAsset>,
+        pub upload_count: u128,
+        pub uploads: SHashMap<u128, StableUpload>,
+        pub config: Config,
+        pub usage: SHashMap<Principal, Usage>,
+        pub quotas: SHashMap<Principal, Quota>,
+        pub roles: SHashMap<Principal, Role>,
+        pub url_secret: Option<[u8; 32]>,
+    }
+}
+
+/// Replaces the CRC32 of pending chunks with their SHA-256.
+fn v9_to_v10(key: usize) {
+    let mut old = retrieve_custom_data::<v9::State>(key)
+        .expect("state not found in stable memory")
+        .into_inner();
+    let mut chunks = SHashMap::new_with_capacity(old.chunks.len())
+        .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    let ids: Vec<u128> = old.chunks.iter().map(|(id, _)| *id).collect();
+    for id in ids {
+        let chunk = old.chunks.remove(&id).unwrap();
+        let bytes: Vec<u8> = chunk.content.iter().map(|b| *b).collect();
+        let chunk = StableChunk {
+            content: chunk.content,
+            owner: chunk.owner,
+            created_at: chunk.created_at,
+            upload_id: chunk.upload_id,
+            index: chunk.index,
+            sha256: Sha256::digest(&bytes).into(),
+            id: chunk.id,
+        };
+        chunks
+            .insert(id, chunk)
+            .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    }
+
+    let new = v10::State {
+        chunk_count: old.chunk_count,
+        chunks,
+        asset_count: old.asset_count,
+        assets: old.assets,
+        upload_count: old.upload_count,
+        uploads: old.uploads,
+        config: old.config,
+        usage: old.usage,
+        quotas: old.quotas,
+        roles: old.roles,
+        url_secret: old.url_secret,
+    };
+    let boxed = SBox::new(new).unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    store_custom_data(key, boxed);
+}
+
+/// Layouts as they were before assets had their own `Cache-Control`.
+mod v10 {
+    use candid::Principal;
+    use ic_stable_memory::{
+        collections::SHashMap,
+        derive::{AsFixedSizeBytes, StableType},
+    };
+
+    use super::{v2::StableUpload, v5::Config, v9::StableAsset};
+    use crate::types::{Quota, Role, StableChunk, Usage};
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct State {
+        pub chunk_count: u128,
+        pub chunks: SHashMap<u128, StableChunk>,
+        pub asset_count: u128,
+        pub assets: SHashMap<u128, StableAsset>,
+        pub upload_count: u128,
+        pub uploads: SHashMap<u128, StableUpload>,
+        pub config: Config,
+        pub usage: SHashMap<Principal, Usage>,
+        pub quotas: SHashMap<Principal, Quota>,
+        pub roles: SHashMap<Principal, Role>,
+        pub url_secret: Option<[u8; 32]>,
+    }
+}
+
+/// Leaves the `Cache-Control` of existing assets unset, so they keep being served with
+/// the default.
+fn v10_to_v11(key: usize) {
+    let mut old = retrieve_custom_data::<v10::State>(key)
+        .expect("state not found in stable memory")
+        .into_inner();
+    let mut assets = SHashMap::new_with_capacity(old.assets.len())
+        .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    let ids: Vec<u128> = old.assets.iter().map(|(id, _)| *id).collect();
+    for id in ids {
+        let asset = old.assets.remove(&id).unwrap();
+        let asset = v11::StableAsset {
+            content: asset.content,
+            file_name: asset.file_name,
+            owner: asset.owner,
+            content_encoding: asset.content_encoding,
+            url: asset.url,
+            chunk_size: asset.chunk_size,
+            id: asset.id,
+            content_type: asset.content_type,
+            sha256: asset.sha256,
+            visibility: asset.visibility,
+            acl: asset.acl,
+            url_key_version: asset.url_key_version,
+            created_at: asset.created_at,
+            updated_at: asset.updated_at,
+            total_length: asset.total_length,
+            description: asset.description,
+            cache_control: None,
+        };
+        assets
+            .insert(id, asset)
+            .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    }
+
+    let new = v11::State {
+        chunk_count: old.chunk_count,
+        chunks: old.chunks,
+        asset_count: old.asset_count,
+        assets,
+        upload_count: old.upload_count,
+        uploads: old.uploads,
+        config: old.config,
+        usage: old.usage,
+        quotas: old.quotas,
+        roles: old.roles,
+        url_secret: old.url_secret,
+    };
+    let boxed = SBox::new(new).unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    store_custom_data(key, boxed);
+}
+
+/// Layouts as they were before an asset could be stored in several encodings.
+mod v11 {
+    use candid::Principal;
+    use ic_stable_memory::{
+        collections::{SHashMap, SVec},
+        derive::{AsFixedSizeBytes, StableType},
+    };
+
+    use super::{v2::StableUpload, v5::Config};
+    use crate::types::{
+        ContentEncoding, Permission, Quota, Role, StableChunk, StableString, Usage, Visibility,
+    };
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct StableAsset {
+        pub content: SHashMap<u32, SVec<u8>>,
+        pub file_name: StableString,
+        pub owner: Principal,
+        pub content_encoding: ContentEncoding,
+        pub url: StableString,
+        pub chunk_size: u32,
+        pub id: u128,
+        pub content_type: StableString,
+        pub sha256: [u8; 32],
+        pub visibility: Visibility,
+        pub acl: SHashMap<Principal, Permission>,
+        pub url_key_version: u32,
+        pub created_at: u64,
+        pub updated_at: u64,
+        pub total_length: u64,
+        pub description: Option<StableString>,
+        pub cache_control: Option<StableString>,
+    }
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct State {
+        pub chunk_count: u128,
+        pub chunks: SHashMap<u128, StableChunk>,
+        pub asset_count: u128,
+        pub assets: SHashMap<u128, StableAsset>,
+        pub upload_count: u128,
+        pub uploads: SHashMap<u128, StableUpload>,
+        pub config: Config,
+        pub usage: SHashMap<Principal, Usage>,
+        pub quotas: SHashMap<Principal, Quota>,
+        pub roles: SHashMap<Principal, Role>,
+        pub url_secret: Option<[u8; 32]>,
+    }
+}
+
+/// Moves the content of every asset into its only encoding, the one it was uploaded in.
+fn v11_to_v12(key: usize) {
+    let mut old = retrieve_custom_data::<v11::State>(key)
+        .expect("state not found in stable memory")
+        .into_inner();
+    let mut assets = SHashMap::new_with_capacity(old.assets.len())
+        .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    let ids: Vec<u128> = old.assets.iter().map(|(id, _)| *id).collect();
+    for id in ids {
+        let asset = old.assets.remove(&id).unwrap();
+        let mut encodings = SHashMap::new();
+        let encoding = StableEncoding {
+            content: asset.content,
+            chunk_count: asset.chunk_size,
+            total_length: asset.total_length,
+            sha256: asset.sha256,
+        };
+        encodings
+            .insert(asset.content_encoding, encoding)
+            .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+        let asset = v14::StableAsset {
+            encodings,
+            file_name: asset.file_name,
+            owner: asset.owner,
+            url: asset.url,
+            id: asset.id,
+            content_type: asset.content_type,
+            visibility: asset.visibility,
+            acl: asset.acl,
+            url_key_version: asset.url_key_version,
+            created_at: asset.created_at,
+            updated_at: asset.updated_at,
+            description: asset.description,
+            cache_control: asset.cache_control,
+        };
+        assets
+            .insert(id, asset)
+            .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    }
+
+    let new = v12::State {
+        chunk_count: old.chunk_count,
+        chunks: old.chunks,
+        asset_count: old.asset_count,
+        assets,
+        upload_count: old.upload_count,
+        uploads: old.uploads,
+        config: old.config,
+        usage: old.usage,
+        quotas: old.quotas,
+        roles: old.roles,
+        url_secret: old.url_secret,
+    };
+    let boxed = SBox::new(new).unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    store_custom_data(key, boxed);
+}
+
+/// Layouts as they were before assets could be compressed by the canister.
+mod v12 {
+    use candid::Principal;
+    use ic_stable_memory::{
+        collections::SHashMap,
+        derive::{AsFixedSizeBytes, StableType},
+    };
+
+    use super::{v14::StableAsset, v2::StableUpload, v5::Config};
+    use crate::types::{Quota, Role, StableChunk, Usage};
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct State {
+        pub chunk_count: u128,
+        pub chunks: SHashMap<u128, StableChunk>,
+        pub asset_count: u128,
+        pub assets: SHashMap<u128, StableAsset>,
+        pub upload_count: u128,
+        pub uploads: SHashMap<u128, StableUpload>,
+        pub config: Config,
+        pub usage: SHashMap<Principal, Usage>,
+        pub quotas: SHashMap<Principal, Quota>,
+        pub roles: SHashMap<Principal, Role>,
+        pub url_secret: Option<[u8; 32]>,
+    }
+}
+
+/// Starts out with no compressions pending.
+fn v12_to_v13(key: usize) {
+    let old = retrieve_custom_data::<v12::State>(key)
+        .expect("state not found in stable memory")
+        .into_inner();
+    let new = v13::State {
+        chunk_count: old.chunk_count,
+        chunks: old.chunks,
+        asset_count: old.asset_count,
+        assets: old.assets,
+        upload_count: old.upload_count,
+        uploads: old.uploads,
+        config: old.config,
+        usage: old.usage,
+        quotas: old.quotas,
+        roles: old.roles,
+        url_secret: old.url_secret,
+        pending_compressions: SVec::new(),
+    };
+    let boxed = SBox::new(new).unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    store_custom_data(key, boxed);
+}
+
+/// Layouts as they were before cross-origin requests could be configured.
+mod v13 {
+    use candid::Principal;
+    use ic_stable_memory::{
+        collections::{SHashMap, SVec},
+        derive::{AsFixedSizeBytes, StableType},
+    };
+
+    use super::{v14::StableAsset, v2::StableUpload, v5::Config};
+    use crate::types::{Quota, Role, StableChunk, Usage};
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct State {
+        pub chunk_count: u128,
+        pub chunks: SHashMap<u128, StableChunk>,
+        pub asset_count: u128,
+        pub assets: SHashMap<u128, StableAsset>,
+        pub upload_count: u128,
+        pub uploads: SHashMap<u128, StableUpload>,
+        pub config: Config,
+        pub usage: SHashMap<Principal, Usage>,
+        pub quotas: SHashMap<Principal, Quota>,
+        pub roles: SHashMap<Principal, Role>,
+        pub url_secret: Option<[u8; 32]>,
+        pub pending_compressions: SVec<u128>,
+    }
+}
+
+/// Starts out without any origin allowed, so responses stay as they were.
+fn v13_to_v14(key: usize) {
+    let old = retrieve_custom_data::<v13::State>(key)
+        .expect("state not found in stable memory")
+        .into_inner();
+    let new = v14::State {
+        chunk_count: old.chunk_count,
+        chunks: old.chunks,
+        asset_count: old.asset_count,
+        assets: old.assets,
+        upload_count: old.upload_count,
+        uploads: old.uploads,
+        config: v14::Config {
+            upload_ttl: old.config.upload_ttl,
+            default_quota: old.config.default_quota,
+            default_role: old.config.default_role,
+            cors: StableCors::default(),
+        },
+        usage: old.usage,
+        quotas: old.quotas,
+        roles: old.roles,
+        url_secret: old.url_secret,
+        pending_compressions: old.pending_compressions,
+    };
+    let boxed = SBox::new(new).unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    store_custom_data(key, boxed);
+}
+
+/// Layouts as they were before asset URLs were computed from the settings.
+mod v14 {
+    use candid::Principal;
+    use ic_stable_memory::{
+        collections::{SHashMap, SVec},
+        derive::{AsFixedSizeBytes, StableType},
+    };
+
+    use super::v2::StableUpload;
+    use crate::types::{
+        ContentEncoding, Permission, Quota, Role, StableChunk, StableCors, StableEncoding,
+        StableString, Usage, Visibility,
+    };
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct Config {
+        pub upload_ttl: u64,
+        pub default_quota: Quota,
+        pub default_role: Option<Role>,
+        pub cors: StableCors,
+    }
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct StableAsset {
+        pub encodings: SHashMap<ContentEncoding, StableEncoding>,
+        pub file_name: StableString,
+        pub owner: Principal,
+        pub url: StableString,
+        pub id: u128,
+        pub content_type: StableString,
+        pub visibility: Visibility,
+        pub acl: SHashMap<Principal, Permission>,
+        pub url_key_version: u32,
+        pub created_at: u64,
+        pub updated_at: u64,
+        pub description: Option<StableString>,
+        pub cache_control: Option<StableString>,
+    }
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct State {
+        pub chunk_count: u128,
+        pub chunks: SHashMap<u128, StableChunk>,
+        pub asset_count: u128,
+        pub assets: SHashMap<u128, StableAsset>,
+        pub upload_count: u128,
+        pub uploads: SHashMap<u128, StableUpload>,
+        pub config: Config,
+        pub usage: SHashMap<Principal, Usage>,
+        pub quotas: SHashMap<Principal, Quota>,
+        pub roles: SHashMap<Principal, Role>,
+        pub url_secret: Option<[u8; 32]>,
+        pub pending_compressions: SVec<u128>,
+    }
+}
+
+/// Drops the URL stored with every asset; they are built from the settings instead,
+/// which start out like the URLs generated so far.
+fn v14_to_v15(key: usize) {
+    let mut old = retrieve_custom_data::<v14::State>(key)
+        .expect("state not found in stable memory")
+        .into_inner();
+    let mut assets = SHashMap::new_with_capacity(old.assets.len())
+        .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    let ids: Vec<u128> = old.assets.iter().map(|(id, _)| *id).collect();
+    for id in ids {
+        let asset = old.assets.remove(&id).unwrap();
+        let asset = v16::StableAsset {
+            encodings: asset.encodings,
+            file_name: asset.file_name,
+            owner: asset.owner,
+            id: asset.id,
+            content_type: asset.content_type,
+            visibility: asset.visibility,
+            acl: asset.acl,
+            url_key_version: asset.url_key_version,
+            created_at: asset.created_at,
+            updated_at: asset.updated_at,
+            description: asset.description,
+            cache_control: asset.cache_control,
+        };
+        assets
+            .insert(id, asset)
+            .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    }
+
+    let new = v15::State {
+        chunk_count: old.chunk_count,
+        chunks: old.chunks,
+        asset_count: old.asset_count,
+        assets,
+        upload_count: old.upload_count,
+        uploads: old.uploads,
+        config: v17::Config {
+            upload_ttl: old.config.upload_ttl,
+            default_quota: old.config.default_quota,
+            default_role: old.config.default_role,
+            cors: old.config.cors,
+            urls: StableUrlConfig::default(),
+        },
+        usage: old.usage,
+        quotas: old.quotas,
+        roles: old.roles,
+        url_secret: old.url_secret,
+        pending_compressions: old.pending_compressions,
+    };
+    let boxed = SBox::new(new).unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    store_custom_data(key, boxed);
+}
+
+/// Layouts as they were before assets could have aliases.
+mod v15 {
+    use candid::Principal;
+    use ic_stable_memory::{
+        collections::{SHashMap, SVec},
+        derive::{AsFixedSizeBytes, StableType},
+    };
+
+    use super::{v16::StableAsset, v17::Config, v2::StableUpload};
+    use crate::types::{Quota, Role, StableChunk, Usage};
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct State {
+        pub chunk_count: u128,
+        pub chunks: SHashMap<u128, StableChunk>,
+        pub asset_count: u128,
+        pub assets: SHashMap<u128, StableAsset>,
+        pub upload_count: u128,
+        pub uploads: SHashMap<u128, StableUpload>,
+        pub config: Config,
+        pub usage: SHashMap<Principal, Usage>,
+        pub quotas: SHashMap<Principal, Quota>,
+        pub roles: SHashMap<Principal, Role>,
+        pub url_secret: Option<[u8; 32]>,
+        pub pending_compressions: SVec<u128>,
+    }
+}
+
+/// Starts out without aliases.
+fn v15_to_v16(key: usize) {
+    let old = retrieve_custom_data::<v15::State>(key)
+        .expect("state not found in stable memory")
+        .into_inner();
+    let new = v16::State {
+        chunk_count: old.chunk_count,
+        chunks: old.chunks,
+        asset_count: old.asset_count,
+        assets: old.assets,
+        upload_count: old.upload_count,
+        uploads: old.uploads,
+        config: old.config,
+        usage: old.usage,
+        quotas: old.quotas,
+        roles: old.roles,
+        url_secret: old.url_secret,
+        pending_compressions: old.pending_compressions,
+        aliases: SHashMap::new(),
+    };
+    let boxed = SBox::new(new).unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    store_custom_data(key, boxed);
+}
+
+/// Layouts as they were before assets had a disposition.
+mod v16 {
+    use candid::Principal;
+    use ic_stable_memory::{
+        collections::{SHashMap, SVec},
+        derive::{AsFixedSizeBytes, StableType},
+    };
+
+    use super::{v17::Config, v2::StableUpload};
+    use crate::types::{
+        ContentEncoding, Permission, Quota, Role, StableChunk, StableEncoding, StableString, Usage,
+        Visibility,
+    };
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct StableAsset {
+        pub encodings: SHashMap<ContentEncoding, StableEncoding>,
+        pub file_name: StableString,
+        pub owner: Principal,
+        pub id: u128,
+        pub content_type: StableString,
+        pub visibility: Visibility,
+        pub acl: SHashMap<Principal, Permission>,
+        pub url_key_version: u32,
+        pub created_at: u64,
+        pub updated_at: u64,
+        pub description: Option<StableString>,
+        pub cache_control: Option<StableString>,
+    }
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct State {
+        pub chunk_count: u128,
+        pub chunks: SHashMap<u128, StableChunk>,
+        pub asset_count: u128,
+        pub assets: SHashMap<u128, StableAsset>,
+        pub upload_count: u128,
+        pub uploads: SHashMap<u128, StableUpload>,
+        pub config: Config,
+        pub usage: SHashMap<Principal, Usage>,
+        pub quotas: SHashMap<Principal, Quota>,
+        pub roles: SHashMap<Principal, Role>,
+        pub url_secret: Option<[u8; 32]>,
+        pub pending_compressions: SVec<u128>,
+        pub aliases: SHashMap<StableString, u128>,
+    }
+}
+
+/// Keeps serving every asset as an attachment, as before.
+fn v16_to_v17(key: usize) {
+    let mut old = retrieve_custom_data::<v16::State>(key)
+        .expect("state not found in stable memory")
+        .into_inner();
+    let mut assets = SHashMap::new_with_capacity(old.assets.len())
+        .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    let ids: Vec<u128> = old.assets.iter().map(|(id, _)| *id).collect();
+    for id in ids {
+        let asset = old.assets.remove(&id).unwrap();
+        let asset = StableAsset {
+            encodings: asset.encodings,
+            file_name: asset.file_name,
+            owner: asset.owner,
+            id: asset.id,
+            content_type: asset.content_type,
+            visibility: asset.visibility,
+            acl: asset.acl,
+            url_key_version: asset.url_key_version,
+            created_at: asset.created_at,
+            updated_at: asset.updated_at,
+            description: asset.description,
+            cache_control: asset.cache_control,
+            disposition: Disposition::Attachment,
+        };
+        assets
+            .insert(id, asset)
+            .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    }
+
+    let new = v17::State {
+        chunk_count: old.chunk_count,
+        chunks: old.chunks,
+        asset_count: old.asset_count,
+        assets,
+        upload_count: old.upload_count,
+        uploads: old.uploads,
+        config: old.config,
+        usage: old.usage,
+        quotas: old.quotas,
+        roles: old.roles,
+        url_secret: old.url_secret,
+        pending_compressions: old.pending_compressions,
+        aliases: old.aliases,
+    };
+    let boxed = SBox::new(new).unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    store_custom_data(key, boxed);
+}
+
+/// Layouts as they were before the body size of HTTP responses was configurable.
+mod v17 {
+    use candid::Principal;
+    use ic_stable_memory::{
+        collections::{SHashMap, SVec},
+        derive::{AsFixedSizeBytes, StableType},
+    };
+
+    use super::v2::StableUpload;
+    use crate::types::{
+        Quota, Role, StableAsset, StableChunk, StableCors, StableString, StableUrlConfig, Usage,
+    };
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct Config {
+        pub upload_ttl: u64,
+        pub default_quota: Quota,
+        pub default_role: Option<Role>,
+        pub cors: StableCors,
+        pub urls: StableUrlConfig,
+    }
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct State {
+        pub chunk_count: u128,
+        pub chunks: SHashMap<u128, StableChunk>,
+        pub asset_count: u128,
+        pub assets: SHashMap<u128, StableAsset>,
+        pub upload_count: u128,
+        pub uploads: SHashMap<u128, StableUpload>,
+        pub config: Config,
+        pub usage: SHashMap<Principal, Usage>,
+        pub quotas: SHashMap<Principal, Quota>,
+        pub roles: SHashMap<Principal, Role>,
+        pub url_secret: Option<[u8; 32]>,
+        pub pending_compressions: SVec<u128>,
+        pub aliases: SHashMap<StableString, u128>,
+    }
+}
+
+/// Starts out with the default body size.
+fn v17_to_v18(key: usize) {
+    let old = retrieve_custom_data::<v17::State>(key)
+        .expect("state not found in stable memory")
+        .into_inner();
+    let new = v18::State {
+        chunk_count: old.chunk_count,
+        chunks: old.chunks,
+        asset_count: old.asset_count,
+        assets: old.assets,
+        upload_count: old.upload_count,
+        uploads: old.uploads,
+        config: Config {
+            upload_ttl: old.config.upload_ttl,
+            default_quota: old.config.default_quota,
+            default_role: old.config.default_role,
+            cors: old.config.cors,
+            urls: old.config.urls,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+        },
+        usage: old.usage,
+        quotas: old.quotas,
+        roles: old.roles,
+        url_secret: old.url_secret,
+        pending_compressions: old.pending_compressions,
+        aliases: old.aliases,
+    };
+    let boxed = SBox::new(new).unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    store_custom_data(key, boxed);
+}
+
+mod v18 {
+    use candid::Principal;
+    use ic_stable_memory::{
+        collections::{SHashMap, SVec},
+        derive::{AsFixedSizeBytes, StableType},
+    };
+
+    use super::v2::StableUpload;
+    use crate::types::{Config, Quota, Role, StableAsset, StableChunk, StableString, Usage};
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct State {
+        pub chunk_count: u128,
+        pub chunks: SHashMap<u128, StableChunk>,
+        pub asset_count: u128,
+        pub assets: SHashMap<u128, StableAsset>,
+        pub upload_count: u128,
+        pub uploads: SHashMap<u128, StableUpload>,
+        pub config: Config,
+        pub usage: SHashMap<Principal, Usage>,
+        pub quotas: SHashMap<Principal, Quota>,
+        pub roles: SHashMap<Principal, Role>,
+        pub url_secret: Option<[u8; 32]>,
+        pub pending_compressions: SVec<u128>,
+        pub aliases: SHashMap<StableString, u128>,
+    }
+}
+
+/// Assets still waiting for gzip get deflate queued as well, as compression now
+/// generates both.
+fn v18_to_v19(key: usize) {
+    let old = retrieve_custom_data::<v18::State>(key)
+        .expect("state not found in stable memory")
+        .into_inner();
+    let mut pending_encodings = SVec::new_with_capacity(old.pending_compressions.len() * 2)
+        .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    for asset_id in old.pending_compressions.iter() {
+        for encoding in [ContentEncoding::GZIP, ContentEncoding::Deflate] {
+            pending_encodings
+                .push((*asset_id, encoding))
+                .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+        }
+    }
+    let new = v20::State {
+        chunk_count: old.chunk_count,
+        chunks: old.chunks,
+        asset_count: old.asset_count,
+        assets: old.assets,
+        upload_count: old.upload_count,
+        uploads: old.uploads,
+        config: old.config,
+        usage: old.usage,
+        quotas: old.quotas,
+        roles: old.roles,
+        url_secret: old.url_secret,
+        pending_encodings,
+        aliases: old.aliases,
+    };
+    let boxed = SBox::new(new).unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    store_custom_data(key, boxed);
+}
+
+/// Layouts as they were before assets were indexed by owner and file name.
+mod v20 {
+    use candid::Principal;
+    use ic_stable_memory::{
+        collections::{SHashMap, SVec},
+        derive::{AsFixedSizeBytes, StableType},
+    };
+
+    use super::v2::StableUpload;
+    use crate::types::{
+        Config, ContentEncoding, Quota, Role, StableAsset, StableChunk, StableString, Usage,
+    };
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct State {
+        pub chunk_count: u128,
+        pub chunks: SHashMap<u128, StableChunk>,
+        pub asset_count: u128,
+        pub assets: SHashMap<u128, StableAsset>,
+        pub upload_count: u128,
+        pub uploads: SHashMap<u128, StableUpload>,
+        pub config: Config,
+        pub usage: SHashMap<Principal, Usage>,
+        pub quotas: SHashMap<Principal, Quota>,
+        pub roles: SHashMap<Principal, Role>,
+        pub url_secret: Option<[u8; 32]>,
+        pub pending_encodings: SVec<(u128, ContentEncoding)>,
+        pub aliases: SHashMap<StableString, u128>,
+    }
+}
+
+/// Used to queue identity content to be decoded for every asset stored without any.
+/// Decoding is now opted into at commit, see `CommitUploadArg::decode`, so existing
+/// assets are left as they are and the layout is unchanged.
+fn v19_to_v20(_key: usize) {}
+
+/// Indexes every asset under its owner and file name, the latest one winning.
+fn v20_to_v21(key: usize) {
+    let old = retrieve_custom_data::<v20::State>(key)
+        .expect("state not found in stable memory")
+        .into_inner();
+    let mut latest: HashMap<String, u128> = HashMap::new();
+    for (id, asset) in old.assets.iter() {
+        let latest_id = latest
+            .entry(name_key(&asset.owner, &asset.file_name))
+            .or_insert(*id);
+        *latest_id = (*latest_id).max(*id);
+    }
+    let mut names = SHashMap::new();
+    for (name, id) in latest {
+        let name = StableString::new(name).unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+        names
+            .insert(name, id)
+            .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    }
+    let new = v21::State {
+        chunk_count: old.chunk_count,
+        chunks: old.chunks,
+        asset_count: old.asset_count,
+        assets: old.assets,
+        upload_count: old.upload_count,
+        uploads: old.uploads,
+        config: old.config,
+        usage: old.usage,
+        quotas: old.quotas,
+        roles: old.roles,
+        url_secret: old.url_secret,
+        pending_encodings: old.pending_encodings,
+        aliases: old.aliases,
+        names,
+    };
+    let boxed = SBox::new(new).unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    store_custom_data(key, boxed);
+}
+
+/// Layouts as they were before upload sessions were hashed as their chunks arrived.
+mod v21 {
+    use candid::Principal;
+    use ic_stable_memory::{
+        collections::{SHashMap, SVec},
+        derive::{AsFixedSizeBytes, StableType},
+    };
+
+    use super::v2::StableUpload;
+    use crate::types::{
+        Config, ContentEncoding, Quota, Role, StableAsset, StableChunk, StableString, Usage,
+    };
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    pub struct State {
+        pub chunk_count: u128,
+        pub chunks: SHashMap<u128, StableChunk>,
+        pub asset_count: u128,
+        pub assets: SHashMap<u128, StableAsset>,
+        pub upload_count: u128,
+        pub uploads: SHashMap<u128, StableUpload>,
+        pub config: Config,
+        pub usage: SHashMap<Principal, Usage>,
+        pub quotas: SHashMap<Principal, Quota>,
+        pub roles: SHashMap<Principal, Role>,
+        pub url_secret: Option<[u8; 32]>,
+        pub pending_encodings: SVec<(u128, ContentEncoding)>,
+        pub aliases: SHashMap<StableString, u128>,
+        pub names: SHashMap<StableString, u128>,
+    }
+}
+
+/// Gives pending upload sessions an empty running hash, so their chunks get hashed
+/// again when they are committed.
+fn v21_to_v22(key: usize) {
+    let mut old = retrieve_custom_data::<v21::State>(key)
+        .expect("state not found in stable memory")
+        .into_inner();
+    let mut uploads = SHashMap::new_with_capacity(old.uploads.len())
+        .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    let ids: Vec<u128> = old.uploads.iter().map(|(id, _)| *id).collect();
+    for id in ids {
+        let upload = old.uploads.remove(&id).unwrap();
+        let upload = StableUpload {
+            id: upload.id,
+            owner: upload.owner,
+            file_name: upload.file_name,
+            content_type: upload.content_type,
+            content_encoding: upload.content_encoding,
+            total_size: upload.total_size,
+            chunks: upload.chunks,
+            created_at: upload.created_at,
+            updated_at: upload.updated_at,
+            hasher: StableSha256::default(),
+            hashed_chunks: 0,
+        };
+        uploads
+            .insert(id, upload)
+            .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    }
+
+    let new = State {
+        chunk_count: old.chunk_count,
+        chunks: old.chunks,
+        asset_count: old.asset_count,
+        assets: old.assets,
+        upload_count: old.upload_count,
+        uploads,
+        config: old.config,
+        usage: old.usage,
+        quotas: old.quotas,
+        roles: old.roles,
+        url_secret: old.url_secret,
+        pending_encodings: old.pending_encodings,
+        aliases: old.aliases,
+        names: old.names,
+    };
+    let boxed = SBox::new(new).unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+    store_custom_data(key, boxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_stable_memory::{
+        collections::SVec,
+        derive::{AsFixedSizeBytes, StableType},
+        stable_memory_init,
+    };
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    struct V1 {
+        count: u32,
+    }
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    struct V2 {
+        count: u32,
+        total: u64,
+    }
+
+    #[derive(StableType, AsFixedSizeBytes)]
+    struct V3 {
+        total: u64,
+    }
+
+    fn v1_to_v2(key: usize) {
+        let old = retrieve_custom_data::<V1>(key).unwrap().into_inner();
+        let new = V2 {
+            count: old.count,
+            total: old.count as u64 * 10,
+        };
+        store_custom_data(key, SBox::new(new).ok().unwrap());
+    }
+
+    fn v2_to_v3(key: usize) {
+        let old = retrieve_custom_data::<V2>(key).unwrap().into_inner();
+        let new = V3 {
+            total: old.total + old.count as u64,
+        };
+        store_custom_data(key, SBox::new(new).ok().unwrap());
+    }
+
+    #[test]
+    fn runs_every_step_from_the_stored_version() {
+        stable_memory_init();
+        store_custom_data(0, SBox::new(V1 { count: 3 }).ok().unwrap());
+
+        run_steps(0, 1, &[v1_to_v2, v2_to_v3]);
+
+        let state = retrieve_custom_data::<V3>(0).unwrap().into_inner();
+        assert_eq!(state.total, 33);
+    }
+
+    #[test]
+    fn skips_steps_already_applied() {
+        stable_memory_init();
+        store_custom_data(0, SBox::new(V2 { count: 1, total: 5 }).ok().unwrap());
+
+        run_steps(0, 2, &[v1_to_v2, v2_to_v3]);
+
+        let state = retrieve_custom_data::<V3>(0).unwrap().into_inner();
+        assert_eq!(state.total, 6);
+    }
+
+    #[test]
+    fn v1_to_v2_drops_pending_chunks() {
+        stable_memory_init();
+        let mut chunks = SHashMap::new();
+        let chunk = v1::StableChunk {
+            content: SVec::new(),
+            owner: candid::Principal::anonymous(),
+            created_at: 0,
+            order: 0,
+            checksum: 0,
+            id: 4,
+        };
+        chunks.insert(4, chunk).ok().unwrap();
+        let old = v1::State {
+            chunk_count: 5,
+            chunks,
+            asset_count: 2,
+            assets: SHashMap::new(),
+        };
+        store_custom_data(0, SBox::new(old).ok().unwrap());
+
+        migrate(0, 1);
+
+        let state = retrieve_custom_data::<State>(0).unwrap().into_inner();
+        assert!(state.chunks.is_empty());
+        assert_eq!((state.chunk_count, state.asset_count), (5, 2));
+        assert_eq!(state.upload_count, 1);
+        assert_eq!(state.config.upload_ttl, DEFAULT_UPLOAD_TTL);
+    }
+}
@@ -0,0 +1,163 @@
+use candid::{candid_method, CandidType, Principal};
+use ic_cdk_macros::{query, update};
+
+use crate::{
+    access_control::{is_admin, is_reader},
+    memory::STATE,
+    types::{Quota, State, StorageError, Usage},
+};
+
+#[derive(CandidType)]
+pub struct UsageQuery {
+    pub usage: Usage,
+    pub quota: Quota,
+}
+
+pub(crate) fn quota_of(state: &State, principal: &Principal) -> Quota {
+    state
+        .quotas
+        .get(principal)
+        .map_or(state.config.default_quota, |quota| *quota)
+}
+
+pub(crate) fn usage_of(state: &State, principal: &Principal) -> Usage {
+    state
+        .usage
+        .get(principal)
+        .map_or_else(Usage::default, |usage| *usage)
+}
+
+/// Fails if storing `bytes` more for `principal` would go over its byte quota.
+pub(crate) fn check_bytes(
+    state: &State,
+    principal: &Principal,
+    bytes: u64,
+) -> Result<(), StorageError> {
+    let limit = quota_of(state, principal).max_bytes;
+    let requested = usage_of(state, principal).bytes.saturating_add(bytes);
+    if requested > limit {
+        return Err(StorageError::StorageQuotaExceeded { limit, requested });
+    }
+    Ok(())
+}
+
+/// Fails if `principal` already holds as many assets as its quota allows.
+pub(crate) fn check_asset(state: &State, principal: &Principal) -> Result<(), StorageError> {
+    let limit = quota_of(state, principal).max_assets;
+    if usage_of(state, principal).assets >= limit {
+        return Err(StorageError::AssetQuotaExceeded { limit });
+    }
+    Ok(())
+}
+
+/// Adds to the usage of `principal`. Callers have already changed the state, so running
+/// out of memory traps to roll the call back.
+pub(crate) fn charge(state: &mut State, principal: &Principal, bytes: u64, assets: u64) {
+    let mut usage = usage_of(state, principal);
+    usage.bytes = usage.bytes.saturating_add(bytes);
+    usage.assets = usage.assets.saturating_add(assets);
+    state
+        .usage
+        .insert(*principal, usage)
+        .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory"));
+}
+
+pub(crate) fn release(state: &mut State, principal: &Principal, bytes: u64, assets: u64) {
+    if let Some(mut usage) = state.usage.get_mut(principal) {
+        usage.bytes = usage.bytes.saturating_sub(bytes);
+        usage.assets = usage.assets.saturating_sub(assets);
+    }
+}
+
+#[update(guard = "is_admin")]
+#[candid_method(update)]
+pub fn set_default_quota(quota: Quota) {
+    STATE.with(|state| state.borrow_mut().config.default_quota = quota);
+}
+
+/// Gives `principal` a quota of its own, or puts it back on the default with `None`.
+#[update(guard = "is_admin")]
+#[candid_method(update)]
+pub fn set_quota(principal: Principal, quota: Option<Quota>) -> Result<(), StorageError> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        match quota {
+            None => {
+                state.quotas.remove(&principal);
+            }
+            Some(quota) => {
+                state
+                    .quotas
+                    .insert(principal, quota)
+                    .map_err(|_| StorageError::OutOfMemory)?;
+            }
+        }
+        Ok(())
+    })
+}
+
+#[query(guard = "is_admin")]
+#[candid_method(query)]
+pub fn get_usage(principal: Principal) -> UsageQuery {
+    STATE.with(|state| {
+        let state = state.borrow();
+        UsageQuery {
+            usage: usage_of(&state, &principal),
+            quota: quota_of(&state, &principal),
+        }
+    })
+}
+
+#[query(guard = "is_reader")]
+#[candid_method(query)]
+pub fn get_my_usage() -> UsageQuery {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let state = state.borrow();
+        UsageQuery {
+            usage: usage_of(&state, &caller),
+            quota: quota_of(&state, &caller),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_stable_memory::stable_memory_init;
+
+    #[test]
+    fn tracks_usage_against_the_quota() {
+        stable_memory_init();
+        let mut state = State::default();
+        let owner = Principal::anonymous();
+        state
+            .quotas
+            .insert(
+                owner,
+                Quota {
+                    max_bytes: 10,
+                    max_assets: 1,
+                },
+            )
+            .unwrap();
+
+        charge(&mut state, &owner, 8, 1);
+        assert_eq!(check_bytes(&state, &owner, 2), Ok(()));
+        assert_eq!(
+            check_bytes(&state, &owner, 3),
+            Err(StorageError::StorageQuotaExceeded {
+                limit: 10,
+                requested: 11
+            })
+        );
+        assert_eq!(
+            check_asset(&state, &owner),
+            Err(StorageError::AssetQuotaExceeded { limit: 1 })
+        );
+
+        release(&mut state, &owner, 8, 1);
+        assert_eq!(check_bytes(&state, &owner, 10), Ok(()));
+        assert_eq!(check_asset(&state, &owner), Ok(()));
+    }
+}
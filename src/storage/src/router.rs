@@ -0,0 +1,155 @@
+use candid::Principal;
+
+use crate::utils::percent_decode;
+
+/// What a request path served by `http_request` points at.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Route {
+    /// `/asset/{id}`, optionally followed by a file name. Only the former is certified
+    /// for public assets, the latter serves byte ranges too.
+    Asset(u128),
+    /// `/by-name/{owner}/{path}`: the asset `owner` most recently committed under the
+    /// file name `path`, which may contain slashes.
+    ByName { owner: Principal, file_name: String },
+    /// `/.well-known/ic-domains`, listing the custom domain for its registration with
+    /// the boundary nodes.
+    WellKnownDomains,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum RouteError {
+    /// The path has the shape of a route, but one of its parameters is malformed.
+    BadRequest,
+    /// No route has this shape.
+    NotFound,
+}
+
+/// Matches a request path, without its query string, against the routes.
+pub(crate) fn route(path: &str) -> Result<Route, RouteError> {
+    let segments = path
+        .strip_prefix('/')
+        .ok_or(RouteError::BadRequest)?
+        .split('/')
+        // decoded one by one, so an escaped `/` stays within its segment
+        .map(percent_decode)
+        .collect::<Option<Vec<String>>>()
+        .ok_or(RouteError::BadRequest)?;
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+    match segments.as_slice() {
+        ["asset", id] | ["asset", id, _] => id
+            .parse()
+            .map(Route::Asset)
+            .map_err(|_| RouteError::BadRequest),
+        ["by-name", owner, path @ ..]
+            if !path.is_empty() && path.iter().all(|segment| !segment.is_empty()) =>
+        {
+            Ok(Route::ByName {
+                owner: Principal::from_text(owner).map_err(|_| RouteError::BadRequest)?,
+                file_name: path.join("/"),
+            })
+        }
+        [".well-known", "ic-domains"] => Ok(Route::WellKnownDomains),
+        ["asset", ..] | ["by-name", ..] => Err(RouteError::BadRequest),
+        _ => Err(RouteError::NotFound),
+    }
+}
+
+/// Longest alias, in bytes.
+const MAX_ALIAS_LENGTH: usize = 1024;
+/// First segments of the built-in routes, which aliases cannot shadow.
+const RESERVED_SEGMENTS: [&str; 3] = ["asset", "by-name", ".well-known"];
+
+/// Joins `segments` into a path with a single leading slash, skipping empty segments.
+fn normalize_segments<'a>(segments: impl Iterator<Item = &'a str>) -> Option<String> {
+    let segments: Vec<&str> = segments.filter(|segment| !segment.is_empty()).collect();
+    let invalid = |segment: &&str| {
+        *segment == "." || *segment == ".." || segment.chars().any(char::is_control)
+    };
+    if segments.is_empty() || segments.iter().any(invalid) {
+        return None;
+    }
+    let path = format!("/{}", segments.join("/"));
+    (path.len() <= MAX_ALIAS_LENGTH).then_some(path)
+}
+
+/// Normalized form of an alias given by an asset owner, `None` if it cannot be one.
+/// Aliases are given unescaped; `docs//report.pdf/` becomes `/docs/report.pdf`.
+pub(crate) fn normalize_alias(alias: &str) -> Option<String> {
+    if alias.contains(['?', '#']) {
+        return None;
+    }
+    let path = normalize_segments(alias.split('/'))?;
+    let first = path[1..].split('/').next().unwrap_or_default();
+    (!RESERVED_SEGMENTS.contains(&first)).then_some(path)
+}
+
+/// Alias a request path, without its query string, would be served under.
+pub(crate) fn alias_of(path: &str) -> Option<String> {
+    let segments = path
+        .split('/')
+        .map(percent_decode)
+        .collect::<Option<Vec<String>>>()?;
+    normalize_segments(segments.iter().map(String::as_str))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_routes() {
+        assert_eq!(route("/asset/42"), Ok(Route::Asset(42)));
+        assert_eq!(route("/asset/42/report%202024.pdf"), Ok(Route::Asset(42)));
+        let owner = Principal::anonymous();
+        assert_eq!(
+            route(&format!("/by-name/{owner}/docs/a%2Fb.txt")),
+            Ok(Route::ByName {
+                owner,
+                file_name: "docs/a/b.txt".to_string()
+            })
+        );
+
+        assert_eq!(
+            route("/.well-known/ic-domains"),
+            Ok(Route::WellKnownDomains)
+        );
+        assert_eq!(route("/"), Err(RouteError::NotFound));
+        assert_eq!(route("/favicon.ico"), Err(RouteError::NotFound));
+        assert_eq!(route("/asset/abc"), Err(RouteError::BadRequest));
+        assert_eq!(route("/asset/1/a/b"), Err(RouteError::BadRequest));
+        assert_eq!(route("/asset/%zz"), Err(RouteError::BadRequest));
+        assert_eq!(
+            route("/by-name/not-a-principal/a"),
+            Err(RouteError::BadRequest)
+        );
+        assert_eq!(
+            route(&format!("/by-name/{owner}")),
+            Err(RouteError::BadRequest)
+        );
+        assert_eq!(
+            route(&format!("/by-name/{owner}/a//b")),
+            Err(RouteError::BadRequest)
+        );
+    }
+
+    #[test]
+    fn normalizes_aliases() {
+        assert_eq!(
+            normalize_alias("docs//2026/report.pdf/").as_deref(),
+            Some("/docs/2026/report.pdf")
+        );
+        assert_eq!(
+            alias_of("/docs/2026/report%20final.pdf").as_deref(),
+            Some("/docs/2026/report final.pdf")
+        );
+        assert_eq!(normalize_alias("/"), None);
+        assert_eq!(normalize_alias("/docs/../secret"), None);
+        assert_eq!(normalize_alias("/docs?x=1"), None);
+        assert_eq!(normalize_alias("/asset/1"), None);
+        assert_eq!(normalize_alias("/.well-known/ic-domains"), None);
+        assert_eq!(
+            normalize_alias("/assets/logo.svg").as_deref(),
+            Some("/assets/logo.svg")
+        );
+    }
+}
@@ -0,0 +1,104 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL, Engine};
+use candid::candid_method;
+use hmac::{Hmac, Mac};
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_cdk_macros::update;
+use sha2::Sha256;
+
+use crate::{
+    access_control::is_uploader,
+    memory::STATE,
+    types::{StableAsset, StorageError, UrlSignature},
+    urls::asset_url,
+    utils::get_query_param,
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+fn mac(secret: &[u8; 32], asset: &StableAsset, expires_at: u64) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(format!("{}:{}:{expires_at}", asset.id, asset.url_key_version).as_bytes());
+    mac
+}
+
+fn sign(secret: &[u8; 32], asset: &StableAsset, expires_at: u64) -> String {
+    BASE64_URL.encode(mac(secret, asset, expires_at).finalize().into_bytes())
+}
+
+/// Whether `signature` was issued for the current key version of `asset` and has not
+/// expired yet.
+pub(crate) fn verify(secret: &[u8; 32], asset: &StableAsset, signature: &UrlSignature) -> bool {
+    if signature.expires_at <= ic_cdk::api::time() / NANOS_PER_SECOND {
+        return false;
+    }
+    let Ok(bytes) = BASE64_URL.decode(&signature.signature) else {
+        return false;
+    };
+    mac(secret, asset, signature.expires_at)
+        .verify_slice(&bytes)
+        .is_ok()
+}
+
+/// Reads the `exp` and `sig` query parameters of a request url.
+pub(crate) fn signature_from_url(url: &str) -> Option<UrlSignature> {
+    Some(UrlSignature {
+        expires_at: get_query_param(url, "exp")?.parse().ok()?,
+        signature: get_query_param(url, "sig")?,
+    })
+}
+
+/// Returns the URL signing key, drawing it from `raw_rand` on first use.
+async fn url_secret() -> Result<[u8; 32], StorageError> {
+    if let Some(secret) = STATE.with(|state| state.borrow().url_secret) {
+        return Ok(secret);
+    }
+    let (bytes,) = raw_rand().await.map_err(|(code, message)| {
+        StorageError::RandomnessFailed(format!("{code:?}: {message}"))
+    })?;
+    let seed: [u8; 32] = bytes
+        .try_into()
+        .map_err(|_| StorageError::RandomnessFailed("expected 32 random bytes".to_string()))?;
+    // a concurrent call may have seeded it while this one was waiting
+    Ok(STATE.with(|state| *state.borrow_mut().url_secret.get_or_insert(seed)))
+}
+
+/// Mints a URL serving the asset over HTTP for `ttl` seconds, whatever its visibility.
+#[update(guard = "is_uploader")]
+#[candid_method(update)]
+pub async fn create_signed_url(asset_id: u128, ttl: u64) -> Result<String, StorageError> {
+    let caller = ic_cdk::caller();
+    let secret = url_secret().await?;
+    let expires_at = (ic_cdk::api::time() / NANOS_PER_SECOND).saturating_add(ttl);
+    STATE.with(|state| {
+        let state = state.borrow();
+        match state.assets.get(&asset_id) {
+            None => Err(StorageError::AssetNotFound),
+            Some(asset) if !asset.is_owner(&caller) => Err(StorageError::NotOwner),
+            Some(asset) => {
+                let signature = sign(&secret, &asset, expires_at);
+                let url = asset_url(&state, &asset);
+                Ok(format!("{url}?exp={expires_at}&sig={signature}"))
+            }
+        }
+    })
+}
+
+/// Invalidates every signed URL handed out for the asset so far.
+#[update(guard = "is_uploader")]
+#[candid_method(update)]
+pub fn revoke_signed_urls(asset_id: u128) -> Result<(), StorageError> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let Some(mut asset) = state.assets.get_mut(&asset_id) else {
+            return Err(StorageError::AssetNotFound);
+        };
+        if !asset.is_owner(&caller) {
+            return Err(StorageError::NotOwner);
+        }
+        asset.url_key_version = asset.url_key_version.wrapping_add(1);
+        Ok(())
+    })
+}
@@ -6,8 +6,9 @@ use ic_stable_memory::{
 };
 // use ic_stable_memory::{collections::SVec, derive::{StableType, AsFixedSizeBytes}};
 // use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
-use crate::chunk_handler::ChunkArg;
+use crate::{chunk_handler::UploadChunkArg, utils::to_hex};
 use serde::Deserialize;
+use sha2::{compress256, digest::generic_array::GenericArray, Digest, Sha256};
 
 pub type StableString = SBox<String>;
 
@@ -26,8 +27,10 @@ pub struct StableChunk {
     pub content: SVec<u8>,
     pub owner: Principal,
     pub created_at: u64,
-    pub order: u32,
-    pub checksum: u32,
+    pub upload_id: u128,
+    pub index: u32,
+    /// SHA-256 of the content, checked against the hash sent by the client.
+    pub sha256: [u8; 32],
     pub id: u128,
 }
 
@@ -35,42 +38,57 @@ pub struct StableChunk {
 pub struct ChunkQuery {
     pub owner: Principal,
     pub created_at: u64,
-    pub order: u32,
-    pub checksum: u32,
+    pub upload_id: u128,
+    pub index: u32,
+    /// Hex-encoded SHA-256 of the content.
+    pub sha256: String,
     pub id: u128,
 }
 
 impl From<&StableChunk> for ChunkQuery {
     fn from(value: &StableChunk) -> Self {
         Self {
-            owner: value.owner.clone(),
+            owner: value.owner,
             created_at: value.created_at,
-            order: value.order,
-            checksum: value.checksum,
+            upload_id: value.upload_id,
+            index: value.index,
+            sha256: to_hex(&value.sha256),
             id: value.id,
         }
     }
 }
 
-impl From<(&Principal, u128, ChunkArg)> for StableChunk {
-    fn from((owner, id, args): (&Principal, u128, ChunkArg)) -> Self {
-        let checksum = crc32fast::hash(&args.content);
+impl TryFrom<(&Principal, u128, UploadChunkArg)> for StableChunk {
+    type Error = StorageError;
+
+    fn try_from(
+        (owner, id, args): (&Principal, u128, UploadChunkArg),
+    ) -> Result<Self, Self::Error> {
+        let sha256: [u8; 32] = Sha256::digest(&args.content).into();
+        if args.sha256 != sha256 {
+            return Err(StorageError::ChunkHashMismatch {
+                index: args.index,
+                expected: to_hex(&args.sha256),
+                actual: to_hex(&sha256),
+            });
+        }
         let content: SVec<u8> = {
-            let mut list =
-                SVec::new_with_capacity(args.content.len()).expect("failed to allocate memory");
+            let mut list = SVec::new_with_capacity(args.content.len())
+                .map_err(|_| StorageError::OutOfMemory)?;
             args.content.iter().for_each(|b| {
-                list.push(b.clone()).unwrap();
+                list.push(*b).unwrap();
             });
             list
         };
-        Self {
+        Ok(Self {
             content,
-            owner: owner.clone(),
+            owner: *owner,
             created_at: ic_cdk::api::time(),
-            order: args.order,
-            checksum,
+            upload_id: args.upload_id,
+            index: args.index,
+            sha256,
             id,
-        }
+        })
     }
 }
 
@@ -94,11 +112,51 @@ impl From<(&Principal, u128, ChunkArg)> for StableChunk {
 // }
 
 #[derive(
-    CandidType, serde::Serialize, serde::Deserialize, Clone, StableType, AsFixedSizeBytes, Debug,
+    CandidType,
+    serde::Serialize,
+    serde::Deserialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    StableType,
+    AsFixedSizeBytes,
+    Debug,
 )]
 pub enum ContentEncoding {
     Identity,
     GZIP,
+    /// The zlib format of RFC 1950, as meant by HTTP's `deflate`.
+    Deflate,
+    Brotli,
+    Zstd,
+}
+
+impl ContentEncoding {
+    /// Name of the encoding in `Accept-Encoding` and `Content-Encoding` headers.
+    pub fn token(&self) -> &'static str {
+        match self {
+            ContentEncoding::Identity => "identity",
+            ContentEncoding::GZIP => "gzip",
+            ContentEncoding::Deflate => "deflate",
+            ContentEncoding::Brotli => "br",
+            ContentEncoding::Zstd => "zstd",
+        }
+    }
+
+    pub fn from_token(token: &str) -> Option<Self> {
+        match token.to_ascii_lowercase().as_str() {
+            "identity" => Some(ContentEncoding::Identity),
+            "gzip" | "x-gzip" => Some(ContentEncoding::GZIP),
+            "deflate" => Some(ContentEncoding::Deflate),
+            "br" => Some(ContentEncoding::Brotli),
+            "zstd" => Some(ContentEncoding::Zstd),
+            _ => None,
+        }
+    }
 }
 
 // impl Storable for ContentEncoding {
@@ -115,16 +173,203 @@ pub enum ContentEncoding {
 //     const MAX_SIZE: u32 = 1;
 // }
 
+/// Who can read an asset besides its owner.
+#[derive(
+    CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, StableType, AsFixedSizeBytes,
+)]
+pub enum Visibility {
+    /// Anyone, including anonymous HTTP requests.
+    Public,
+    /// Nobody but the owner.
+    Private,
+    /// The principals in the asset's ACL.
+    Shared,
+}
+
+/// Whether browsers display an asset or save it, sent as `Content-Disposition`.
+#[derive(
+    CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, StableType, AsFixedSizeBytes,
+)]
+pub enum Disposition {
+    Inline,
+    Attachment,
+}
+
+/// Access granted to a principal on a shared asset; `Write` includes `Read`. Neither
+/// lets it delete the asset or change who can read it.
+#[derive(
+    CandidType,
+    Deserialize,
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    StableType,
+    AsFixedSizeBytes,
+)]
+pub enum Permission {
+    Read,
+    Write,
+}
+
+/// The content of an asset in one encoding, kept in the chunks it was uploaded in.
 #[derive(StableType, AsFixedSizeBytes, Debug)]
-pub struct StableAsset {
+pub struct StableEncoding {
     pub content: SHashMap<u32, SVec<u8>>,
+    pub chunk_count: u32,
+    /// Length in bytes of the content, summed over all of its chunks.
+    pub total_length: u64,
+    /// SHA-256 of the full content, used to certify HTTP responses.
+    pub sha256: [u8; 32],
+}
+
+impl StableEncoding {
+    /// Reads the bytes in `start..=end`, walking the chunks in order.
+    ///
+    /// Both ends are inclusive, matching the `Range`/`Content-Range` headers.
+    pub fn read_range(&self, start: u64, end: u64) -> Vec<u8> {
+        let mut body = Vec::with_capacity((end - start + 1) as usize);
+        let mut chunk_start = 0;
+        for index in 0..self.chunk_count {
+            let Some(chunk) = self.content.get(&index) else {
+                continue;
+            };
+            let chunk_end = chunk_start + chunk.len() as u64;
+            if chunk_end > start {
+                let from = start.saturating_sub(chunk_start);
+                let to = (end + 1).min(chunk_end) - chunk_start;
+                body.extend((from..to).map(|i| *chunk.get(i as usize).unwrap()));
+            }
+            if chunk_end > end {
+                break;
+            }
+            chunk_start = chunk_end;
+        }
+        body
+    }
+
+    /// Strong entity tag of the content, quoted as sent in `ETag` headers.
+    pub fn etag(&self) -> String {
+        format!("\"{}\"", to_hex(&self.sha256))
+    }
+}
+
+#[derive(StableType, AsFixedSizeBytes, Debug)]
+pub struct StableAsset {
+    /// The stored variants of the content, at least one.
+    pub encodings: SHashMap<ContentEncoding, StableEncoding>,
     pub file_name: StableString,
     pub owner: Principal,
-    pub content_encoding: ContentEncoding,
-    pub url: StableString,
-    pub chunk_size: u32,
     pub id: u128,
     pub content_type: StableString,
+    pub visibility: Visibility,
+    /// Permissions of other principals, only used while the asset is `Shared`.
+    pub acl: SHashMap<Principal, Permission>,
+    /// Part of every signed URL; bumping it invalidates the URLs handed out so far.
+    pub url_key_version: u32,
+    /// Time the asset was committed, `0` for assets committed before it was recorded.
+    pub created_at: u64,
+    /// Time the asset or its metadata last changed.
+    pub updated_at: u64,
+    pub description: Option<StableString>,
+    /// `Cache-Control` header served with the asset, `DEFAULT_CACHE_CONTROL` if unset.
+    pub cache_control: Option<StableString>,
+    /// Served unless a request asks for a download with `?download=1`.
+    pub disposition: Disposition,
+}
+
+impl StableAsset {
+    /// Anonymous owns nothing: assets it owned from before uploads took an identity
+    /// would otherwise be private to, and writable by, every unauthenticated caller.
+    pub fn is_owner(&self, principal: &Principal) -> bool {
+        *principal == self.owner && *principal != Principal::anonymous()
+    }
+
+    fn permission_of(&self, principal: &Principal) -> Option<Permission> {
+        if self.is_owner(principal) {
+            return Some(Permission::Write);
+        }
+        match self.visibility {
+            Visibility::Shared => self.acl.get(principal).map(|permission| *permission),
+            Visibility::Public | Visibility::Private => None,
+        }
+    }
+
+    pub fn can_read(&self, principal: &Principal) -> bool {
+        self.visibility == Visibility::Public || self.permission_of(principal).is_some()
+    }
+
+    pub fn can_write(&self, principal: &Principal) -> bool {
+        self.permission_of(principal) == Some(Permission::Write)
+    }
+
+    /// Encodings stored for the asset, in a stable order.
+    pub fn stored_encodings(&self) -> Vec<ContentEncoding> {
+        let mut encodings: Vec<ContentEncoding> = self
+            .encodings
+            .iter()
+            .map(|(encoding, _)| *encoding)
+            .collect();
+        encodings.sort();
+        encodings
+    }
+
+    /// Encoding used when the caller does not pick one: identity if stored.
+    pub fn default_encoding(&self) -> ContentEncoding {
+        self.stored_encodings()[0]
+    }
+
+    /// Bytes taken by all stored encodings together.
+    pub fn stored_bytes(&self) -> u64 {
+        self.encodings
+            .iter()
+            .map(|(_, encoding)| encoding.total_length)
+            .sum()
+    }
+
+    pub fn cache_control(&self) -> String {
+        match &self.cache_control {
+            Some(cache_control) => String::clone(cache_control),
+            None => DEFAULT_CACHE_CONTROL.to_string(),
+        }
+    }
+}
+
+/// Public asset holding `bytes` as identity content in a single chunk.
+#[cfg(test)]
+pub(crate) fn test_asset(id: u128, bytes: &[u8]) -> StableAsset {
+    let mut chunk = SVec::new_with_capacity(bytes.len()).unwrap();
+    bytes.iter().for_each(|b| chunk.push(*b).unwrap());
+    let mut content = SHashMap::new();
+    content.insert(0, chunk).unwrap();
+    let mut encodings = SHashMap::new();
+    let encoding = StableEncoding {
+        content,
+        chunk_count: 1,
+        total_length: bytes.len() as u64,
+        sha256: [7; 32],
+    };
+    encodings
+        .insert(ContentEncoding::Identity, encoding)
+        .unwrap();
+    StableAsset {
+        encodings,
+        file_name: StableString::new("file.txt".to_string()).unwrap(),
+        owner: Principal::anonymous(),
+        id,
+        content_type: StableString::new("text/plain".to_string()).unwrap(),
+        visibility: Visibility::Public,
+        acl: SHashMap::new(),
+        url_key_version: 0,
+        created_at: 0,
+        updated_at: 0,
+        description: None,
+        cache_control: None,
+        disposition: Disposition::Attachment,
+    }
 }
 
 // impl Storable for Asset {
@@ -150,25 +395,495 @@ pub struct StableAsset {
 pub struct AssetQuery {
     pub file_name: String,
     pub owner: Principal,
-    pub content_encoding: ContentEncoding,
     pub url: String,
     pub id: u128,
     pub content_type: String,
+    pub visibility: Visibility,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub encodings: Vec<EncodingQuery>,
+    pub description: Option<String>,
+    pub cache_control: Option<String>,
+    pub disposition: Disposition,
 }
 
-impl From<&StableAsset> for AssetQuery {
-    fn from(value: &StableAsset) -> Self {
+impl AssetQuery {
+    /// `url` is computed from the current URL settings, see `urls::asset_url`.
+    pub fn new(value: &StableAsset, url: String) -> Self {
         Self {
             file_name: value.file_name.clone(),
-            owner: value.owner.clone(),
-            content_encoding: value.content_encoding.clone(),
-            url: value.url.clone(),
+            owner: value.owner,
+            url,
             id: value.id,
             content_type: value.content_type.clone(),
+            visibility: value.visibility,
+            created_at: value.created_at,
+            updated_at: value.updated_at,
+            encodings: value
+                .stored_encodings()
+                .into_iter()
+                .map(|encoding| {
+                    EncodingQuery::new(encoding, &value.encodings.get(&encoding).unwrap())
+                })
+                .collect(),
+            description: value
+                .description
+                .as_ref()
+                .map(|description| String::clone(description)),
+            cache_control: value
+                .cache_control
+                .as_ref()
+                .map(|cache_control| String::clone(cache_control)),
+            disposition: value.disposition,
+        }
+    }
+}
+
+#[derive(CandidType)]
+pub struct EncodingQuery {
+    pub encoding: ContentEncoding,
+    pub total_length: u64,
+    pub chunk_count: u32,
+    /// Hex-encoded SHA-256 of the full content in this encoding.
+    pub sha256: String,
+}
+
+impl EncodingQuery {
+    fn new(encoding: ContentEncoding, value: &StableEncoding) -> Self {
+        Self {
+            encoding,
+            total_length: value.total_length,
+            chunk_count: value.chunk_count,
+            sha256: to_hex(&value.sha256),
+        }
+    }
+}
+
+/// SHA-256 in progress, kept in stable memory so that a file can be hashed a few chunks
+/// at a time rather than all at once.
+#[derive(StableType, AsFixedSizeBytes, Clone, Copy, Debug)]
+pub struct StableSha256 {
+    /// Intermediate hash value, as big-endian words like the final digest.
+    state: [u8; 32],
+    /// Start of the next block, the first `length % 64` bytes of it.
+    block: [u8; 64],
+    length: u64,
+}
+
+/// FIPS 180-4 section 5.3.3
+const SHA256_INITIAL_STATE: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+impl Default for StableSha256 {
+    fn default() -> Self {
+        let mut hasher = Self {
+            state: [0; 32],
+            block: [0; 64],
+            length: 0,
+        };
+        hasher.set_words(SHA256_INITIAL_STATE);
+        hasher
+    }
+}
+
+impl StableSha256 {
+    /// Bytes hashed so far.
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    fn words(&self) -> [u32; 8] {
+        let mut words = [0; 8];
+        for (word, bytes) in words.iter_mut().zip(self.state.chunks_exact(4)) {
+            *word = u32::from_be_bytes(bytes.try_into().unwrap());
+        }
+        words
+    }
+
+    fn set_words(&mut self, words: [u32; 8]) {
+        for (bytes, word) in self.state.chunks_exact_mut(4).zip(words) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+    }
+
+    pub fn update(&mut self, mut bytes: &[u8]) {
+        let mut words = self.words();
+        let filled = (self.length % 64) as usize;
+        self.length += bytes.len() as u64;
+        if filled > 0 {
+            let taken = (64 - filled).min(bytes.len());
+            self.block[filled..filled + taken].copy_from_slice(&bytes[..taken]);
+            bytes = &bytes[taken..];
+            if filled + taken < 64 {
+                return;
+            }
+            compress256(&mut words, &[self.block.into()]);
+        }
+        let blocks = bytes.chunks_exact(64);
+        let rest = blocks.remainder();
+        for block in blocks {
+            compress256(&mut words, &[*GenericArray::from_slice(block)]);
+        }
+        self.block[..rest.len()].copy_from_slice(rest);
+        self.set_words(words);
+    }
+
+    pub fn finalize(mut self) -> [u8; 32] {
+        let length_bits = self.length * 8;
+        // a one bit, then zeros up to the length in the last 8 bytes of a block
+        let mut padding = vec![0x80];
+        padding.resize(1 + ((119 - self.length % 64) % 64) as usize, 0);
+        padding.extend_from_slice(&length_bits.to_be_bytes());
+        self.update(&padding);
+        self.state
+    }
+}
+
+/// A file being uploaded: chunks are attached to it by index until it is committed
+/// into a `StableAsset`.
+#[derive(StableType, AsFixedSizeBytes, Debug)]
+pub struct StableUpload {
+    pub id: u128,
+    pub owner: Principal,
+    pub file_name: StableString,
+    pub content_type: StableString,
+    pub content_encoding: ContentEncoding,
+    /// Size in bytes of the whole file, as declared by the client.
+    pub total_size: u64,
+    /// Chunk index to the id of the chunk in `State::chunks`.
+    pub chunks: SHashMap<u32, u128>,
+    pub created_at: u64,
+    /// Time the last chunk was received.
+    pub updated_at: u64,
+    /// SHA-256 of the first `hashed_chunks` chunks, which commits only have to finish.
+    pub hasher: StableSha256,
+    pub hashed_chunks: u32,
+}
+
+impl StableUpload {
+    /// Chunk indices run below this, the most chunks of `MIN_CHUNK_SIZE` the announced
+    /// size takes.
+    pub fn max_chunk_count(&self) -> u32 {
+        self.total_size
+            .div_ceil(MIN_CHUNK_SIZE)
+            .clamp(1, u32::MAX.into()) as u32
+    }
+}
+
+#[derive(CandidType)]
+pub struct ReceivedChunk {
+    pub index: u32,
+    pub size: u64,
+    /// Hex-encoded SHA-256 of the chunk.
+    pub sha256: String,
+}
+
+/// Progress of an upload session, so a client can resume from the first missing chunk.
+#[derive(CandidType)]
+pub struct UploadQuery {
+    pub id: u128,
+    pub file_name: String,
+    pub content_type: String,
+    pub content_encoding: ContentEncoding,
+    pub total_size: u64,
+    pub received_size: u64,
+    /// Received chunks, sorted by index.
+    pub chunks: Vec<ReceivedChunk>,
+    pub created_at: u64,
+    pub updated_at: u64,
+    /// Time after which the session and its chunks may be cleaned up.
+    pub expires_at: u64,
+}
+
+impl UploadQuery {
+    pub fn new(upload: &StableUpload, chunks: &SHashMap<u128, StableChunk>, ttl: u64) -> Self {
+        let mut received: Vec<ReceivedChunk> = upload
+            .chunks
+            .iter()
+            .filter_map(|(index, id)| {
+                chunks.get(&id).map(|chunk| ReceivedChunk {
+                    index: *index,
+                    size: chunk.content.len() as u64,
+                    sha256: to_hex(&chunk.sha256),
+                })
+            })
+            .collect();
+        received.sort_by_key(|chunk| chunk.index);
+        Self {
+            id: upload.id,
+            file_name: upload.file_name.clone(),
+            content_type: upload.content_type.clone(),
+            content_encoding: upload.content_encoding,
+            total_size: upload.total_size,
+            received_size: received.iter().map(|chunk| chunk.size).sum(),
+            chunks: received,
+            created_at: upload.created_at,
+            updated_at: upload.updated_at,
+            expires_at: upload.updated_at.saturating_add(ttl),
+        }
+    }
+}
+
+/// Smallest size of the chunks of an upload session but its last, which bounds the
+/// chunk indices a session takes.
+pub const MIN_CHUNK_SIZE: u64 = 1024;
+
+/// Time in nanoseconds an upload session is kept after its last chunk, unless configured.
+pub const DEFAULT_UPLOAD_TTL: u64 = 10 * 60 * 1_000_000_000;
+
+/// Body size of HTTP responses unless configured, leaving room for the headers within
+/// the 2 MiB the gateway accepts.
+pub const DEFAULT_MAX_BODY_SIZE: u64 = 2 * 1024 * 1024 - 64 * 1024;
+/// Bounds of the configurable body size. Certificate headers alone take several KiB,
+/// so the body cannot grow into the room left for the headers.
+pub const MIN_BODY_SIZE: u64 = 1024;
+pub const MAX_BODY_SIZE: u64 = DEFAULT_MAX_BODY_SIZE;
+
+/// `Cache-Control` of assets that were not given their own: caches have to revalidate
+/// on every use, which is cheap thanks to the `ETag`.
+pub const DEFAULT_CACHE_CONTROL: &str = "private, max-age=0";
+
+/// Limits applied to a principal's stored data, counting pending chunks and assets.
+#[derive(CandidType, Deserialize, Clone, Copy, StableType, AsFixedSizeBytes, Debug)]
+pub struct Quota {
+    pub max_bytes: u64,
+    pub max_assets: u64,
+}
+
+impl Default for Quota {
+    fn default() -> Self {
+        Self {
+            max_bytes: 4 * 1024 * 1024 * 1024,
+            max_assets: 10_000,
+        }
+    }
+}
+
+/// Data currently held for a principal, checked against its `Quota`.
+#[derive(CandidType, Deserialize, Clone, Copy, Default, StableType, AsFixedSizeBytes, Debug)]
+pub struct Usage {
+    pub bytes: u64,
+    pub assets: u64,
+}
+
+/// Access levels, each including the ones before it.
+#[derive(
+    CandidType,
+    Deserialize,
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    StableType,
+    AsFixedSizeBytes,
+)]
+pub enum Role {
+    Reader,
+    Uploader,
+    Admin,
+    Controller,
+}
+
+/// Settings the admins can change at runtime.
+#[derive(StableType, AsFixedSizeBytes, Debug)]
+pub struct Config {
+    /// Time in nanoseconds an upload session is kept after its last chunk was received.
+    pub upload_ttl: u64,
+    /// Quota of principals without a quota of their own.
+    pub default_quota: Quota,
+    /// Role of principals without a role of their own.
+    pub default_role: Option<Role>,
+    pub cors: StableCors,
+    pub urls: StableUrlConfig,
+    /// Largest body, in bytes, of an HTTP response or streaming callback.
+    pub max_body_size: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            upload_ttl: DEFAULT_UPLOAD_TTL,
+            default_quota: Quota::default(),
+            default_role: Some(Role::Reader),
+            cors: StableCors::default(),
+            urls: StableUrlConfig::default(),
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+        }
+    }
+}
+
+/// Where the canister is reached over HTTP.
+#[derive(
+    CandidType, Deserialize, Clone, Copy, PartialEq, Eq, StableType, AsFixedSizeBytes, Debug,
+)]
+pub enum Network {
+    /// A local replica started by dfx, served over plain HTTP.
+    Local,
+    /// The Internet Computer, served through its boundary nodes.
+    Ic,
+}
+
+/// Gateway domain of `Network::Local` while none is configured.
+pub const DEFAULT_LOCAL_DOMAIN: &str = "localhost:8080";
+/// Gateway domain of `Network::Ic` while none is configured.
+pub const DEFAULT_IC_DOMAIN: &str = "icp0.io";
+
+/// How the URLs handed out for assets are built.
+#[derive(StableType, AsFixedSizeBytes, Debug)]
+pub struct StableUrlConfig {
+    pub network: Network,
+    /// Domain the canister id is prefixed to, such as `icp0.io`; `None` for the
+    /// default of the network.
+    pub gateway_domain: Option<StableString>,
+    /// Domain pointing at the canister, used instead of the gateway domain.
+    pub custom_domain: Option<StableString>,
+    /// Whether to use the `raw` gateway subdomain, which skips response verification.
+    /// Custom domains have no raw counterpart, so this is ignored for them.
+    pub raw: bool,
+}
+
+impl Default for StableUrlConfig {
+    fn default() -> Self {
+        Self {
+            network: Network::Local,
+            gateway_domain: None,
+            custom_domain: None,
+            raw: false,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct UrlConfig {
+    pub network: Network,
+    pub gateway_domain: Option<String>,
+    pub custom_domain: Option<String>,
+    pub raw: bool,
+}
+
+impl From<&StableUrlConfig> for UrlConfig {
+    fn from(urls: &StableUrlConfig) -> Self {
+        Self {
+            network: urls.network,
+            gateway_domain: urls
+                .gateway_domain
+                .as_ref()
+                .map(|domain| String::clone(domain)),
+            custom_domain: urls
+                .custom_domain
+                .as_ref()
+                .map(|domain| String::clone(domain)),
+            raw: urls.raw,
+        }
+    }
+}
+
+impl TryFrom<UrlConfig> for StableUrlConfig {
+    type Error = StorageError;
+
+    fn try_from(urls: UrlConfig) -> Result<Self, Self::Error> {
+        let stable = |domain: Option<String>| -> Result<Option<StableString>, StorageError> {
+            domain
+                .map(|domain| SBox::new(domain).map_err(|_| StorageError::OutOfMemory))
+                .transpose()
+        };
+        Ok(Self {
+            network: urls.network,
+            gateway_domain: stable(urls.gateway_domain)?,
+            custom_domain: stable(urls.custom_domain)?,
+            raw: urls.raw,
+        })
+    }
+}
+
+/// Methods allowed in preflight responses while none are configured.
+pub const DEFAULT_CORS_METHODS: [&str; 3] = ["GET", "HEAD", "OPTIONS"];
+/// Request headers allowed in preflight responses while none are configured.
+pub const DEFAULT_CORS_HEADERS: [&str; 3] = ["Range", "If-None-Match", "If-Modified-Since"];
+/// Seconds browsers may cache a preflight response.
+pub const DEFAULT_CORS_MAX_AGE: u64 = 24 * 60 * 60;
+
+/// Which other origins may read `http_request` responses from a browser.
+#[derive(StableType, AsFixedSizeBytes, Debug)]
+pub struct StableCors {
+    /// Origins such as `https://app.example.com`, or `*` for any origin. Cross-origin
+    /// requests are not allowed while this is empty.
+    pub allowed_origins: SVec<StableString>,
+    /// Empty to allow `DEFAULT_CORS_METHODS`.
+    pub allowed_methods: SVec<StableString>,
+    /// Empty to allow `DEFAULT_CORS_HEADERS`.
+    pub allowed_headers: SVec<StableString>,
+    pub max_age: u64,
+}
+
+impl Default for StableCors {
+    fn default() -> Self {
+        Self {
+            allowed_origins: SVec::new(),
+            allowed_methods: SVec::new(),
+            allowed_headers: SVec::new(),
+            max_age: DEFAULT_CORS_MAX_AGE,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct Cors {
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub max_age: u64,
+}
+
+impl From<&StableCors> for Cors {
+    fn from(cors: &StableCors) -> Self {
+        let strings = |values: &SVec<StableString>| -> Vec<String> {
+            values.iter().map(|value| String::clone(&value)).collect()
+        };
+        Self {
+            allowed_origins: strings(&cors.allowed_origins),
+            allowed_methods: strings(&cors.allowed_methods),
+            allowed_headers: strings(&cors.allowed_headers),
+            max_age: cors.max_age,
         }
     }
 }
 
+impl TryFrom<Cors> for StableCors {
+    type Error = StorageError;
+
+    fn try_from(cors: Cors) -> Result<Self, Self::Error> {
+        let strings = |values: Vec<String>| -> Result<SVec<StableString>, StorageError> {
+            let mut stable = SVec::new();
+            for value in values {
+                let value = SBox::new(value).map_err(|_| StorageError::OutOfMemory)?;
+                stable.push(value).map_err(|_| StorageError::OutOfMemory)?;
+            }
+            Ok(stable)
+        };
+        Ok(Self {
+            allowed_origins: strings(cors.allowed_origins)?,
+            allowed_methods: strings(cors.allowed_methods)?,
+            allowed_headers: strings(cors.allowed_headers)?,
+            max_age: cors.max_age,
+        })
+    }
+}
+
+/// What a cleanup run reclaimed.
+#[derive(CandidType, Default, Debug)]
+pub struct CleanupStats {
+    pub uploads: u64,
+    pub chunks: u64,
+    pub bytes: u64,
+    /// `false` if the run stopped at its instruction budget with expired uploads left.
+    pub finished: bool,
+}
+
 #[derive(StableType, AsFixedSizeBytes)]
 pub struct State {
     pub chunk_count: u128,
@@ -179,6 +894,21 @@ pub struct State {
     // #[serde(skip, default = "init_asset_stable_data")]
     // pub assets: StableBTreeMap<u128, Asset, StableMemory>,
     pub assets: SHashMap<u128, StableAsset>,
+    pub upload_count: u128,
+    pub uploads: SHashMap<u128, StableUpload>,
+    pub config: Config,
+    pub usage: SHashMap<Principal, Usage>,
+    /// Per-principal overrides of `Config::default_quota`.
+    pub quotas: SHashMap<Principal, Quota>,
+    pub roles: SHashMap<Principal, Role>,
+    /// HMAC key of signed URLs, drawn from `raw_rand` when the first URL is signed.
+    pub url_secret: Option<[u8; 32]>,
+    /// Encodings waiting to be generated for assets, oldest first.
+    pub pending_encodings: SVec<(u128, ContentEncoding)>,
+    /// Assets served under paths chosen by their owners, keyed by normalized path.
+    pub aliases: SHashMap<StableString, u128>,
+    /// Latest asset of each owner under each file name, keyed by `name_key`.
+    pub names: SHashMap<StableString, u128>,
 }
 
 impl Default for State {
@@ -188,10 +918,25 @@ impl Default for State {
             chunks: SHashMap::new(),
             asset_count: 1,
             assets: SHashMap::new(),
+            upload_count: 1,
+            uploads: SHashMap::new(),
+            config: Config::default(),
+            usage: SHashMap::new(),
+            quotas: SHashMap::new(),
+            roles: SHashMap::new(),
+            url_secret: None,
+            pending_encodings: SVec::new(),
+            aliases: SHashMap::new(),
+            names: SHashMap::new(),
         }
     }
 }
 
+/// Key of `State::names`. Principals contain no `/`, so the owner ends at the first one.
+pub fn name_key(owner: &Principal, file_name: &str) -> String {
+    format!("{owner}/{file_name}")
+}
+
 impl State {
     pub fn get_chunk_id(&mut self) -> u128 {
         let id = self.chunk_count;
@@ -204,6 +949,81 @@ impl State {
         self.asset_count += 1;
         id
     }
+
+    pub fn get_upload_id(&mut self) -> u128 {
+        let id = self.upload_count;
+        self.upload_count += 1;
+        id
+    }
+}
+
+/// Error returned by every fallible endpoint, so clients can branch on the kind.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum StorageError {
+    NoChunks,
+    ChunkNotFound,
+    ChunksNotFound(Vec<u128>),
+    UploadNotFound,
+    /// Indices missing before the highest uploaded one, at most the first 1000, or, if
+    /// there are none, the one after it when the chunks fall short of the announced size.
+    MissingChunks(Vec<u32>),
+    /// The chunks are still being hashed, `hashed` bytes of them so far; committing
+    /// again hashes more.
+    HashPending {
+        hashed: u64,
+    },
+    /// A chunk index at or above `limit`, see `MIN_CHUNK_SIZE`.
+    ChunkIndexOutOfRange {
+        limit: u32,
+    },
+    SizeMismatch {
+        expected: u64,
+        actual: u64,
+    },
+    /// The chunk at `index` does not hash to the SHA-256 sent with it.
+    ChunkHashMismatch {
+        index: u32,
+        expected: String,
+        actual: String,
+    },
+    /// The chunks of an upload do not hash to the SHA-256 sent to commit it.
+    HashMismatch {
+        expected: String,
+        actual: String,
+    },
+    AssetNotFound,
+    NotOwner,
+    NotAuthorized,
+    StorageQuotaExceeded {
+        limit: u64,
+        requested: u64,
+    },
+    AssetQuotaExceeded {
+        limit: u64,
+    },
+    /// A `Cache-Control` value that cannot be sent as a header.
+    InvalidCacheControl,
+    EncodingNotFound,
+    /// The uploaded content does not start like a stream in its declared encoding.
+    InvalidEncoding(ContentEncoding),
+    /// Removing the encoding would leave the asset without content.
+    LastEncoding,
+    /// A CORS origin, method or header that cannot be sent in a header.
+    InvalidCors,
+    /// A gateway or custom domain that is not a plain host name with an optional port.
+    InvalidDomain,
+    /// A path that cannot be an alias, for instance one of the built-in routes.
+    InvalidAlias,
+    /// The alias already points at an asset of another owner.
+    AliasTaken,
+    AliasNotFound,
+    InvalidBodySize {
+        min: u64,
+        max: u64,
+    },
+    OutOfMemory,
+    CanisterStatusFailed(String),
+    RandomnessFailed(String),
 }
 
 #[derive(CandidType, Deserialize, Clone)]
@@ -225,19 +1045,32 @@ pub struct HttpResponse {
     pub streaming_strategy: Option<StreamingStrategy>,
 }
 
+/// `exp` and `sig` query parameters of a signed asset URL.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct UrlSignature {
+    /// Expiry, in seconds since the epoch.
+    pub expires_at: u64,
+    pub signature: String,
+}
+
 #[derive(CandidType, Deserialize, Clone)]
 pub struct CreateStrategyArgs {
     pub asset_id: u128,
-    pub chunk_index: u32,
-    pub chunk_size: u32,
+    /// Offset of the first byte the callback serves.
+    pub offset: u64,
+    pub content_encoding: ContentEncoding,
+    pub signature: Option<UrlSignature>,
 }
 
 #[derive(CandidType, Deserialize, Clone)]
 pub struct StreamingCallbackToken {
     pub asset_id: u128,
-    pub chunk_index: u32,
-    pub chunk_size: u32,
+    /// Offset of the next byte to serve, in the content of the encoding streamed.
+    pub offset: u64,
+    /// Token of the encoding being streamed, as in `Content-Encoding`.
     pub content_encoding: String,
+    /// Signature of the URL the download started from, checked again for every chunk.
+    pub signature: Option<UrlSignature>,
 }
 
 #[derive(CandidType, Deserialize, Clone)]
@@ -253,3 +1086,27 @@ pub struct StreamingCallbackHttpResponse {
     pub body: Vec<u8>,
     pub token: Option<StreamingCallbackToken>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_like_sha2() {
+        let bytes: Vec<u8> = (0..300u32).map(|i| (i * 7) as u8).collect();
+        for length in [0, 1, 55, 56, 63, 64, 65, 119, 120, 128, 300] {
+            for split in [0, 1, 3, 64, 100].map(|split: usize| split.min(length)) {
+                let mut hasher = StableSha256::default();
+                hasher.update(&bytes[..split]);
+                hasher.update(&bytes[split..length]);
+                assert_eq!(hasher.length(), length as u64);
+                let expected: [u8; 32] = Sha256::digest(&bytes[..length]).into();
+                assert_eq!(
+                    hasher.finalize(),
+                    expected,
+                    "{length} bytes split at {split}"
+                );
+            }
+        }
+    }
+}
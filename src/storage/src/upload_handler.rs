@@ -0,0 +1,130 @@
+use candid::{candid_method, CandidType};
+use ic_cdk_macros::{query, update};
+use ic_stable_memory::collections::SHashMap;
+
+use crate::{
+    access_control::{is_admin, is_reader, is_uploader},
+    memory::STATE,
+    quota::check_bytes,
+    types::{
+        ContentEncoding, StableSha256, StableString, StableUpload, State, StorageError, UploadQuery,
+    },
+};
+
+#[derive(CandidType, serde::Deserialize)]
+pub struct UploadArg {
+    pub file_name: String,
+    pub content_type: String,
+    pub total_size: u64,
+    pub content_encoding: ContentEncoding,
+}
+
+/// Opens an upload session that chunks are then uploaded to by index.
+#[update(guard = "is_uploader")]
+#[candid_method(update)]
+pub fn create_upload(args: UploadArg) -> Result<u128, StorageError> {
+    let caller = ic_cdk::caller();
+    let now = ic_cdk::api::time();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        check_bytes(&state, &caller, args.total_size)?;
+        let id = state.get_upload_id();
+        let upload = StableUpload {
+            id,
+            owner: caller,
+            file_name: StableString::new(args.file_name).map_err(|_| StorageError::OutOfMemory)?,
+            content_type: StableString::new(args.content_type)
+                .map_err(|_| StorageError::OutOfMemory)?,
+            content_encoding: args.content_encoding,
+            total_size: args.total_size,
+            chunks: SHashMap::new(),
+            created_at: now,
+            updated_at: now,
+            hasher: StableSha256::default(),
+            hashed_chunks: 0,
+        };
+        state
+            .uploads
+            .insert(id, upload)
+            .map_err(|_| StorageError::OutOfMemory)?;
+        Ok(id)
+    })
+}
+
+/// Bytes of chunks one call to `hash_chunks` may hash, so that committing a large file
+/// does not have to hash all of it in one message.
+const MAX_HASHED_BYTES: u64 = 32 * 1024 * 1024;
+
+/// Feeds the chunks following the ones already hashed into the running SHA-256 of the
+/// upload, in index order, until one is missing or `MAX_HASHED_BYTES` are hashed.
+pub(crate) fn hash_chunks(state: &mut State, upload_id: u128) {
+    let State {
+        uploads, chunks, ..
+    } = state;
+    let Some(mut upload) = uploads.get_mut(&upload_id) else {
+        return;
+    };
+    let mut hashed = 0;
+    while hashed < MAX_HASHED_BYTES {
+        let Some(chunk) = upload
+            .chunks
+            .get(&upload.hashed_chunks)
+            .and_then(|id| chunks.get(&id))
+        else {
+            break;
+        };
+        let bytes: Vec<u8> = chunk.content.iter().map(|b| *b).collect();
+        upload.hasher.update(&bytes);
+        upload.hashed_chunks += 1;
+        hashed += bytes.len() as u64;
+    }
+}
+
+#[query(guard = "is_uploader")]
+#[candid_method(query)]
+pub fn get_upload(id: u128) -> Result<UploadQuery, StorageError> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let state = state.borrow();
+        match state.uploads.get(&id) {
+            None => Err(StorageError::UploadNotFound),
+            Some(upload) if upload.owner != caller => Err(StorageError::NotOwner),
+            Some(upload) => Ok(UploadQuery::new(
+                &upload,
+                &state.chunks,
+                state.config.upload_ttl,
+            )),
+        }
+    })
+}
+
+/// Upload sessions of the caller that have not been committed yet.
+#[query(guard = "is_uploader")]
+#[candid_method(query)]
+pub fn my_uploads() -> Vec<UploadQuery> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let state = state.borrow();
+        let mut uploads: Vec<UploadQuery> = state
+            .uploads
+            .iter()
+            .filter(|(_, upload)| upload.owner == caller)
+            .map(|(_, upload)| UploadQuery::new(&upload, &state.chunks, state.config.upload_ttl))
+            .collect();
+        uploads.sort_by_key(|upload| upload.id);
+        uploads
+    })
+}
+
+/// Sets how long, in nanoseconds, upload sessions are kept after their last chunk.
+#[update(guard = "is_admin")]
+#[candid_method(update)]
+pub fn set_upload_ttl(ttl: u64) {
+    STATE.with(|state| state.borrow_mut().config.upload_ttl = ttl);
+}
+
+#[query(guard = "is_reader")]
+#[candid_method(query)]
+pub fn get_upload_ttl() -> u64 {
+    STATE.with(|state| state.borrow().config.upload_ttl)
+}
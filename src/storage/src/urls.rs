@@ -0,0 +1,125 @@
+use candid::{candid_method, Principal};
+use ic_cdk_macros::{query, update};
+
+use crate::{
+    access_control::{is_admin, is_reader},
+    certification::asset_path,
+    memory::STATE,
+    types::{
+        Network, StableAsset, StableUrlConfig, State, StorageError, UrlConfig, DEFAULT_IC_DOMAIN,
+        DEFAULT_LOCAL_DOMAIN,
+    },
+    utils::percent_encode,
+};
+
+/// Whether `domain` is a host name, optionally followed by a port.
+fn is_valid_domain(domain: &str) -> bool {
+    let (host, port) = domain.split_once(':').unwrap_or((domain, "80"));
+    !host.is_empty()
+        && host.split('.').all(|label| {
+            !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+        && port.parse::<u16>().is_ok()
+}
+
+/// Base URL the paths of `canister_id` are served under, without a trailing slash.
+fn base_url(urls: &UrlConfig, canister_id: &Principal) -> String {
+    if let Some(domain) = &urls.custom_domain {
+        return format!("https://{domain}");
+    }
+    let (scheme, default_domain) = match urls.network {
+        Network::Local => ("http", DEFAULT_LOCAL_DOMAIN),
+        Network::Ic => ("https", DEFAULT_IC_DOMAIN),
+    };
+    let domain = urls.gateway_domain.as_deref().unwrap_or(default_domain);
+    let raw = if urls.raw { "raw." } else { "" };
+    format!("{scheme}://{canister_id}.{raw}{domain}")
+}
+
+/// Path handed out for an asset: `/asset/{id}/{file_name}`. Unlike the certified
+/// `asset_path`, it serves byte ranges, which players need to seek.
+fn file_path(asset_id: u128, file_name: &str) -> String {
+    format!("{}/{}", asset_path(asset_id), percent_encode(file_name))
+}
+
+/// URL serving the asset under the current settings.
+pub(crate) fn asset_url(state: &State, asset: &StableAsset) -> String {
+    let urls = UrlConfig::from(&state.config.urls);
+    let path = file_path(asset.id, &asset.file_name);
+    format!("{}{path}", base_url(&urls, &ic_cdk::id()))
+}
+
+/// Sets how asset URLs are built. URLs are computed whenever they are read, so this
+/// applies to every asset right away.
+#[update(guard = "is_admin")]
+#[candid_method(update)]
+pub fn set_url_config(urls: UrlConfig) -> Result<(), StorageError> {
+    let domains = [&urls.gateway_domain, &urls.custom_domain];
+    if domains
+        .into_iter()
+        .flatten()
+        .any(|domain| !is_valid_domain(domain))
+    {
+        return Err(StorageError::InvalidDomain);
+    }
+    let urls = StableUrlConfig::try_from(urls)?;
+    STATE.with(|state| state.borrow_mut().config.urls = urls);
+    Ok(())
+}
+
+#[query(guard = "is_reader")]
+#[candid_method(query)]
+pub fn get_url_config() -> UrlConfig {
+    STATE.with(|state| UrlConfig::from(&state.borrow().config.urls))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_base_urls() {
+        let canister_id = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+        let mut urls = UrlConfig {
+            network: Network::Local,
+            gateway_domain: None,
+            custom_domain: None,
+            raw: false,
+        };
+        assert_eq!(
+            base_url(&urls, &canister_id),
+            "http://ryjl3-tyaaa-aaaaa-aaaba-cai.localhost:8080"
+        );
+        urls.network = Network::Ic;
+        urls.raw = true;
+        assert_eq!(
+            base_url(&urls, &canister_id),
+            "https://ryjl3-tyaaa-aaaaa-aaaba-cai.raw.icp0.io"
+        );
+        urls.gateway_domain = Some("ic0.app".to_string());
+        assert_eq!(
+            base_url(&urls, &canister_id),
+            "https://ryjl3-tyaaa-aaaaa-aaaba-cai.raw.ic0.app"
+        );
+        urls.custom_domain = Some("files.example.com".to_string());
+        assert_eq!(base_url(&urls, &canister_id), "https://files.example.com");
+
+        assert!(is_valid_domain("localhost:4943"));
+        assert!(is_valid_domain("files.example.com"));
+        assert!(!is_valid_domain("https://files.example.com"));
+        assert!(!is_valid_domain("files.example.com/path"));
+        assert!(!is_valid_domain("localhost:port"));
+        assert!(!is_valid_domain(""));
+    }
+
+    #[test]
+    fn hands_out_paths_serving_ranges() {
+        assert_eq!(file_path(7, "report.pdf"), "/asset/7/report.pdf");
+        let path = file_path(7, "docs/my report.pdf");
+        assert_eq!(path, "/asset/7/docs%2Fmy%20report.pdf");
+        assert_eq!(
+            crate::router::route(&path),
+            Ok(crate::router::Route::Asset(7))
+        );
+    }
+}
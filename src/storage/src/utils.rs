@@ -1,24 +1,439 @@
-use crate::types::StableString;
+use crate::types::{ContentEncoding, Disposition};
 
-const IN_PROD: bool = false;
+/// Request url without its query string, as used for certification paths.
+pub(crate) fn get_path(url: &str) -> &str {
+    url.split('?').next().unwrap_or_default()
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Formats a time in nanoseconds since the epoch as an HTTP date, for instance
+/// `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub(crate) fn http_date(nanos: u64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    let seconds = nanos / 1_000_000_000;
+    let (days, seconds_of_day) = (seconds / 86_400, seconds % 86_400);
+
+    // civil-from-days conversion, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{}, {day:02} {} {year} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        MONTHS[(month - 1) as usize],
+        seconds_of_day / 3_600,
+        seconds_of_day % 3_600 / 60,
+        seconds_of_day % 60,
+    )
+}
+
+/// Parses an HTTP date in the format written by [`http_date`] into seconds since the
+/// epoch. The obsolete RFC 850 and asctime formats are not accepted.
+pub(crate) fn parse_http_date(value: &str) -> Option<u64> {
+    let mut parts = value.split_whitespace().skip(1);
+    let day: i64 = parts.next()?.parse().ok()?;
+    let month = parts.next()?;
+    let month = MONTHS.iter().position(|name| *name == month)? as i64 + 1;
+    let year: i64 = parts.next()?.parse().ok()?;
+    let mut time = parts
+        .next()?
+        .split(':')
+        .map(|part| part.parse::<u64>().ok());
+    let (hours, minutes, seconds) = (time.next()??, time.next()??, time.next()??);
+    if parts.next() != Some("GMT") || !(1..=31).contains(&day) || year < 1970 {
+        return None;
+    }
+
+    // days-from-civil conversion, the inverse of the one in `http_date`
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    Some(days as u64 * 86_400 + hours * 3_600 + minutes * 60 + seconds)
+}
+
+/// Decodes the `%XX` escapes of a URL component, failing on malformed escapes and on
+/// bytes that are not UTF-8.
+pub(crate) fn percent_decode(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Escapes every byte of `value` but the unreserved characters of RFC 3986, so that it
+/// fits in a single path segment.
+pub(crate) fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            true => (byte as char).to_string(),
+            false => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+/// Decoded name-value pairs of the query string of a request url, in order. `None` if
+/// one of them is not validly encoded.
+pub(crate) fn parse_query(url: &str) -> Option<Vec<(String, String)>> {
+    let Some((_, query)) = url.split_once('?') else {
+        return Some(vec![]);
+    };
+    let decode = |component: &str| percent_decode(&component.replace('+', " "));
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Some((decode(name)?, decode(value)?))
+        })
+        .collect()
+}
+
+/// Decoded value of the query parameter `name` in a request url, if present.
+pub(crate) fn get_query_param(url: &str, name: &str) -> Option<String> {
+    parse_query(url)?
+        .into_iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value)
+}
+
+/// Longest file name kept at commit, in characters.
+const MAX_FILE_NAME_LENGTH: usize = 255;
+
+/// File name as stored at commit: without control characters, which could end a
+/// header early, without surrounding whitespace and cut to a sane length.
+pub(crate) fn sanitize_file_name(name: &str) -> String {
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+    let name: String = name.trim().chars().take(MAX_FILE_NAME_LENGTH).collect();
+    match name.is_empty() {
+        true => "file".to_string(),
+        false => name,
+    }
+}
+
+/// `Content-Disposition` value offering `file_name` as described in RFC 6266: an ASCII
+/// `filename` for old clients and the exact name as an RFC 8187 `filename*`.
+pub(crate) fn content_disposition(disposition: Disposition, file_name: &str) -> String {
+    let kind = match disposition {
+        Disposition::Inline => "inline",
+        Disposition::Attachment => "attachment",
+    };
+    // clients only use the last path segment anyway
+    let name = match file_name.rsplit(['/', '\\']).next() {
+        Some(name) if !name.is_empty() => name,
+        _ => "file",
+    };
+    let mut fallback = String::new();
+    for c in name.chars() {
+        match c {
+            '"' | '\\' => {
+                fallback.push('\\');
+                fallback.push(c);
+            }
+            ' '..='~' => fallback.push(c),
+            _ => fallback.push('_'),
+        }
+    }
+    // attr-char of RFC 8187 section 3.2.1, everything else is percent-encoded
+    let is_attr_char = |byte: u8| byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte);
+    let encoded: String = name
+        .bytes()
+        .map(|byte| match is_attr_char(byte) {
+            true => (byte as char).to_string(),
+            false => format!("%{byte:02X}"),
+        })
+        .collect();
+    format!("{kind}; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+}
+
+/// Outcome of matching a `Range` header against an asset of known length.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum RangeRequest {
+    /// No usable range: serve the whole asset.
+    Full,
+    /// Serve bytes `start..=end`.
+    Partial { start: u64, end: u64 },
+    /// The range lies outside of the asset: respond with `416`.
+    Unsatisfiable,
+}
 
-pub(crate) fn generate_url(asset_id: u128) -> StableString{
-    let canister_id = ic_cdk::id();
-    match IN_PROD{
-        true => {
-            let url = format!("https://{canister_id}.raw.ic0.app/asset/{asset_id}");
-            StableString::new(url).unwrap()
+/// Parses a single `bytes=` range as described in RFC 9110 section 14.1.2.
+///
+/// Malformed headers, other units and multi-range requests are ignored, which the
+/// RFC allows, so the caller falls back to a full `200` response.
+pub(crate) fn parse_range(header: &str, total_length: u64) -> RangeRequest {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return RangeRequest::Full;
+    };
+    if spec.contains(',') {
+        return RangeRequest::Full;
+    }
+    let Some((start, end)) = spec.split_once('-') else {
+        return RangeRequest::Full;
+    };
+    let (start, end) = (start.trim(), end.trim());
+
+    if start.is_empty() {
+        // suffix range: the last `end` bytes
+        return match end.parse::<u64>() {
+            Err(_) => RangeRequest::Full,
+            Ok(0) => RangeRequest::Unsatisfiable,
+            Ok(_) if total_length == 0 => RangeRequest::Unsatisfiable,
+            Ok(suffix) => RangeRequest::Partial {
+                start: total_length.saturating_sub(suffix),
+                end: total_length - 1,
+            },
+        };
+    }
+
+    let Ok(start) = start.parse::<u64>() else {
+        return RangeRequest::Full;
+    };
+    let end = match end {
+        "" => None,
+        end => match end.parse::<u64>() {
+            Ok(end) if end >= start => Some(end),
+            _ => return RangeRequest::Full,
         },
-        false => {
-            let url = format!("http://{canister_id}.localhost:8080/asset/{asset_id}");
-            StableString::new(url).unwrap()
+    };
+    if start >= total_length {
+        return RangeRequest::Unsatisfiable;
+    }
+    RangeRequest::Partial {
+        start,
+        end: end.map_or(total_length - 1, |end| end.min(total_length - 1)),
+    }
+}
+
+/// Stored encodings in the order they are served when a client accepts several of them
+/// equally, smallest output first.
+const ENCODING_PREFERENCE: [ContentEncoding; 5] = [
+    ContentEncoding::Brotli,
+    ContentEncoding::Zstd,
+    ContentEncoding::GZIP,
+    ContentEncoding::Deflate,
+    ContentEncoding::Identity,
+];
+
+/// Picks the stored encoding to serve for an `Accept-Encoding` header, as described in
+/// RFC 9110 section 12.5.3. `None` means that none of them is acceptable.
+pub(crate) fn select_encoding(
+    header: Option<&str>,
+    stored: &[ContentEncoding],
+) -> Option<ContentEncoding> {
+    let preferred = ENCODING_PREFERENCE
+        .into_iter()
+        .filter(|encoding| stored.contains(encoding));
+    let Some(header) = header else {
+        // any encoding is acceptable, but clients sending no header rarely decode one
+        return stored
+            .contains(&ContentEncoding::Identity)
+            .then_some(ContentEncoding::Identity)
+            .or_else(|| preferred.clone().next());
+    };
+
+    let mut weights = vec![];
+    let mut wildcard = None;
+    for item in header.split(',') {
+        let mut params = item.split(';');
+        let token = params.next().unwrap_or_default().trim();
+        let weight = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if token == "*" {
+            wildcard = Some(weight);
+        } else if let Some(encoding) = ContentEncoding::from_token(token) {
+            weights.push((encoding, weight));
+        }
+    }
+    let weight_of = |encoding: ContentEncoding| {
+        weights
+            .iter()
+            .find(|(listed, _)| *listed == encoding)
+            .map(|(_, weight)| *weight)
+            .or(wildcard)
+            // identity stays acceptable unless excluded explicitly
+            .unwrap_or(if encoding == ContentEncoding::Identity {
+                1.0
+            } else {
+                0.0
+            })
+    };
+
+    let mut best: Option<(ContentEncoding, f32)> = None;
+    for encoding in preferred {
+        let weight = weight_of(encoding);
+        if weight > 0.0 && best.is_none_or(|(_, best)| weight > best) {
+            best = Some((encoding, weight));
         }
     }
+    best.map(|(encoding, _)| encoding)
 }
 
-pub(crate) fn get_asset_id(url: String) -> u128{
-    let url_split_by_path = url.split('/').collect::<Vec<&str>>();
-    let last_elem = url_split_by_path[url_split_by_path.len() - 1];
-    let first_elem: Vec<&str> = last_elem.split('?').collect();
-    first_elem[0].trim().parse::<u128>().unwrap()
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_byte_ranges() {
+        assert_eq!(
+            parse_range("bytes=0-99", 1000),
+            RangeRequest::Partial { start: 0, end: 99 }
+        );
+        assert_eq!(
+            parse_range("bytes=900-", 1000),
+            RangeRequest::Partial { start: 900, end: 999 }
+        );
+        assert_eq!(
+            parse_range("bytes=-100", 1000),
+            RangeRequest::Partial { start: 900, end: 999 }
+        );
+        assert_eq!(
+            parse_range("bytes=500-5000", 1000),
+            RangeRequest::Partial { start: 500, end: 999 }
+        );
+        assert_eq!(
+            parse_range("bytes=-5000", 1000),
+            RangeRequest::Partial { start: 0, end: 999 }
+        );
+    }
+
+    #[test]
+    fn rejects_unsatisfiable_ranges() {
+        assert_eq!(parse_range("bytes=1000-", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn ignores_unsupported_ranges() {
+        assert_eq!(parse_range("items=0-1", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=10-5", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=abc", 1000), RangeRequest::Full);
+    }
+
+    #[test]
+    fn reads_query_params() {
+        let url = "/asset/7?exp=1700000000&sig=abc-_";
+        assert_eq!(get_query_param(url, "exp").as_deref(), Some("1700000000"));
+        assert_eq!(get_query_param(url, "sig").as_deref(), Some("abc-_"));
+        assert_eq!(get_query_param(url, "id"), None);
+        assert_eq!(get_query_param("/asset/7", "exp"), None);
+        assert_eq!(
+            get_query_param("/by-name/x/y?name=a%20b+c&flag", "name").as_deref(),
+            Some("a b c")
+        );
+        assert_eq!(get_query_param("/asset/7?name=%zz", "name"), None);
+    }
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(percent_decode("a%2Fb%20c").as_deref(), Some("a/b c"));
+        assert_eq!(percent_decode("caf%C3%A9").as_deref(), Some("café"));
+        assert_eq!(percent_decode("100%"), None);
+        assert_eq!(percent_decode("%FF"), None);
+        assert_eq!(percent_encode("docs/a b~.pdf"), "docs%2Fa%20b~.pdf");
+        assert_eq!(percent_decode(&percent_encode("café ?#%")).as_deref(), Some("café ?#%"));
+    }
+
+    #[test]
+    fn formats_content_dispositions() {
+        assert_eq!(
+            content_disposition(Disposition::Inline, "photo.png"),
+            "inline; filename=\"photo.png\"; filename*=UTF-8''photo.png"
+        );
+        assert_eq!(
+            content_disposition(Disposition::Attachment, "docs/my \"report\" é.pdf"),
+            "attachment; filename=\"my \\\"report\\\" _.pdf\"; \
+             filename*=UTF-8''my%20%22report%22%20%C3%A9.pdf"
+        );
+        assert_eq!(
+            sanitize_file_name(" a.txt\r\nSet-Cookie: x "),
+            "a.txtSet-Cookie: x"
+        );
+        assert_eq!(sanitize_file_name("\n"), "file");
+    }
+
+    #[test]
+    fn formats_http_dates() {
+        assert_eq!(http_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(
+            http_date(784_111_777 * 1_000_000_000),
+            "Sun, 06 Nov 1994 08:49:37 GMT"
+        );
+        assert_eq!(
+            http_date(1_709_210_096 * 1_000_000_000),
+            "Thu, 29 Feb 2024 12:34:56 GMT"
+        );
+    }
+
+    #[test]
+    fn parses_http_dates() {
+        assert_eq!(parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(784_111_777)
+        );
+        assert_eq!(
+            parse_http_date("Thu, 29 Feb 2024 12:34:56 GMT"),
+            Some(1_709_210_096)
+        );
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49"), None);
+    }
+
+    #[test]
+    fn selects_accepted_encodings() {
+        use ContentEncoding::{Brotli, Identity, Zstd, GZIP};
+
+        let both = [Identity, GZIP];
+        assert_eq!(select_encoding(None, &both), Some(Identity));
+        assert_eq!(select_encoding(None, &[GZIP]), Some(GZIP));
+        assert_eq!(
+            select_encoding(Some("gzip, deflate, br"), &both),
+            Some(GZIP)
+        );
+        assert_eq!(
+            select_encoding(Some("gzip;q=0.5, identity"), &both),
+            Some(Identity)
+        );
+        assert_eq!(select_encoding(Some("*"), &both), Some(GZIP));
+        assert_eq!(select_encoding(Some("br"), &both), Some(Identity));
+        assert_eq!(select_encoding(Some("br"), &[GZIP]), None);
+        assert_eq!(select_encoding(Some("gzip;q=0, *;q=0"), &both), None);
+        assert_eq!(
+            select_encoding(Some("gzip, zstd, br"), &[GZIP, Brotli, Zstd]),
+            Some(Brotli)
+        );
+    }
+}
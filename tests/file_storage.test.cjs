@@ -1,18 +1,19 @@
 const test = require("tape");
 const { Ed25519KeyIdentity } = require("@dfinity/identity");
+const crypto = require("crypto");
 const fs = require("fs");
 const path = require("path");
 const mime = require("mime");
-const { updateChecksum } = require("./utils.cjs");
+const { sha256 } = require("./utils.cjs");
 
 // Actor Interface
 const {
-  idlFactory: file_storage_interface,
-} = require("../.dfx/local/canisters/file_storage/file_storage.did.test.cjs");
+  idlFactory: storage_interface,
+} = require("../.dfx/local/canisters/storage/storage.did.test.cjs");
 
 // Canister Ids
 const canister_ids = require("../.dfx/local/canister_ids.json");
-const file_storage_canister_id = canister_ids.file_storage.local;
+const storage_canister_id = canister_ids.storage.local;
 
 // Identities
 let motoko_identity = Ed25519KeyIdentity.generate();
@@ -20,303 +21,210 @@ let dom_identity = Ed25519KeyIdentity.generate();
 
 const { getActor } = require("./actor.cjs");
 
-let file_storage_actors = {};
+let storage_actors = {};
 
+const chunkSize = 2000000;
+
+let upload_id;
 let chunk_ids = [];
-let checksum = 0;
+let file_hash;
+
+// Opens an upload session for `bytes` and uploads them in chunks of `chunkSize`.
+const uploadFile = async (actor, { file_name, content_type, bytes }) => {
+  const { Ok: id, Err: error } = await actor.create_upload({
+    file_name,
+    content_type,
+    total_size: bytes.length,
+    content_encoding: { Identity: null },
+  });
+  if (error !== undefined) {
+    throw new Error(JSON.stringify(error));
+  }
+
+  const promises = [];
+  for (let start = 0, index = 0; start < bytes.length; start += chunkSize, index++) {
+    const content = bytes.slice(start, start + chunkSize);
+    promises.push(
+      actor
+        .upload_chunk({ upload_id: id, index, content, sha256: sha256(content) })
+        .then(({ Ok: chunk_id }) => chunk_id)
+    );
+  }
+  return { upload_id: id, chunk_ids: await Promise.all(promises) };
+};
+
+// Commits an upload session, committing again while its chunks are still being hashed.
+const commitUpload = async (actor, upload_id, sha256) => {
+  for (;;) {
+    const result = await actor.commit_upload({
+      upload_id,
+      sha256,
+      visibility: [],
+      description: [],
+      cache_control: [],
+      compress: [],
+      decode: [],
+      disposition: [],
+    });
+    if (result.Err === undefined || result.Err.HashPending === undefined) {
+      return result;
+    }
+  }
+};
 
 test("Setup Actors", async function (t) {
   console.log("=========== File Storage ===========");
 
-  file_storage_actors.motoko = await getActor(
-    file_storage_canister_id,
-    file_storage_interface,
+  storage_actors.motoko = await getActor(
+    storage_canister_id,
+    storage_interface,
     motoko_identity
   );
 
-  file_storage_actors.dom = await getActor(
-    file_storage_canister_id,
-    file_storage_interface,
+  storage_actors.dom = await getActor(
+    storage_canister_id,
+    storage_interface,
     dom_identity
   );
 });
 
-test("pdf upload", async function(t){
-  const uploadChunk = async({chunk, order}) => {
-    return file_storage_actors.motoko.create_chunk(chunk, order);
-  };
-  const file_path = "tests/data/file.pdf";
-  const asset_buffer = fs.readFileSync(file_path);
-  const asset_unit8Array = new Uint8Array(asset_buffer);
-  const promises = [];
-  const chunkSize = 2000000;
-  for (
-    let start = 0, index = 0;
-    start < asset_unit8Array.length;
-    start += chunkSize, index++
-  ) {
-    const chunk = asset_unit8Array.slice(start, start + chunkSize);
-
-    checksum = updateChecksum(chunk, checksum);
+test("Storage[motoko].upload_chunk(): should store chunks of a video file", async function (t) {
+  const bytes = new Uint8Array(crypto.randomBytes(5 * chunkSize + 1234));
 
-    promises.push(
-      uploadChunk({
-        chunk,
-        order: index,
-      })
-    );
-  }
-
-  chunk_ids = await Promise.all(promises);
-
-  const hasChunkIds = chunk_ids.length > 2;
-
-  t.equal(hasChunkIds, true);
-});
-
-test("FileStorage[motoko].commit_batch(): should start formation of asset to be stored", async function (t) {
-  const file_path = "tests/data/file.pdf";
+  ({ upload_id, chunk_ids } = await uploadFile(storage_actors.motoko, {
+    file_name: "bots.mp4",
+    content_type: "video/mp4",
+    bytes,
+  }));
+  file_hash = sha256(bytes);
 
-  const asset_filename = path.basename(file_path);
-  const asset_content_type = mime.getType(file_path);
-  const { Ok: asset_id, Err: error } =
-    await file_storage_actors.motoko.commit_batch(chunk_ids, {
-      filename: asset_filename,
-      checksum: checksum,
-      content_encoding: { Identity: null },
-      content_type: asset_content_type,
-    });
-    const { Ok: asset } = await file_storage_actors.motoko.get(asset_id);
-    // console.log(asset);
-    t.equal(asset.filename, "file.pdf");
-    t.equal(asset.content_type, asset_content_type);
-    // t.equal(asset.checksum, checksum);
-    checksum = 0
-});
+  t.equal(chunk_ids.length > 2, true);
 
-test("FileStorage[motoko].version(): should return version number", async function (t) {
-  const response = await file_storage_actors.motoko.version();
+  const response = await storage_actors.motoko.chunk_availability_check(
+    chunk_ids
+  );
+  t.equal(response, true);
 
-  t.equal(response, 1n);
+  const { Ok: upload } = await storage_actors.motoko.get_upload(upload_id);
+  t.equal(upload.received_size, upload.total_size);
 });
 
-test("FileStorage[motoko].create_chunk(): should store chunk data of video file to canister", async function (t) {
-
-
-  const uploadChunk = async ({ chunk, order }) => {
-    return file_storage_actors.motoko.create_chunk(chunk, order);
-  };
-
-  const file_path = "tests/data/bots.mp4";
-
-  const asset_buffer = fs.readFileSync(file_path);
-
-  const asset_unit8Array = new Uint8Array(asset_buffer);
-
-  const promises = [];
-  const chunkSize = 2000000;
-
-  for (
-    let start = 0, index = 0;
-    start < asset_unit8Array.length;
-    start += chunkSize, index++
-  ) {
-    const chunk = asset_unit8Array.slice(start, start + chunkSize);
-
-    checksum = updateChecksum(chunk, checksum);
-
-    promises.push(
-      uploadChunk({
-        chunk,
-        order: index,
-      })
-    );
-  }
-
-  chunk_ids = await Promise.all(promises);
+test("Storage[dom].commit_upload(): should return error not owner since the upload is motoko's", async function (t) {
+  const { Err: error } = await commitUpload(
+    storage_actors.dom,
+    upload_id,
+    file_hash
+  );
 
-  const hasChunkIds = chunk_ids.length > 2;
-  // for (let i = 0; i < chunk_ids.length; i++){
-  //   let chunk_check = await file_storage_actors.motoko.get_chunk_detail(chunk_ids[i]);
-  //   t.equal(chunk_check, true)
-  // }
-  t.equal(hasChunkIds, true);
+  t.deepEqual(error, { NotOwner: null });
 });
 
-test("FileStorage[dom].commit_batch(): should return error not authorized since not owner of chunks", async function (t) {
-  const file_path = "tests/data/bots.mp4";
-
-  const asset_filename = path.basename(file_path);
-  const asset_content_type = mime.getType(file_path);
-
-  const { Err: error } = await file_storage_actors.dom.commit_batch(
-
-    chunk_ids,
-    {
-      filename: asset_filename,
-      checksum: checksum,
-      content_encoding: { Identity: null },
-      content_type: asset_content_type,
-    }
+test("Storage[motoko].commit_upload(): should err on a hash mismatch", async function (t) {
+  const { Err: error } = await commitUpload(
+    storage_actors.motoko,
+    upload_id,
+    sha256(new Uint8Array([1, 2, 3]))
   );
 
-  t.equal(error, "Caller does not own the chunk.");
+  t.notEqual(error.HashMismatch, undefined);
 });
 
-test("FileStorage[motoko].commit_batch(): should start formation of asset to be stored", async function (t) {
-  const file_path = "tests/data/bots.mp4";
-  const asset_filename = path.basename(file_path);
-  const asset_content_type = mime.getType(file_path);
-
-  const { Ok: asset_id, Err: error } =
-    await file_storage_actors.motoko.commit_batch(chunk_ids, {
-      filename: asset_filename,
-      checksum: checksum,
-      content_encoding: { Identity: null },
-      content_type: asset_content_type,
-    });
-
-  const { Ok: asset } = await file_storage_actors.motoko.get(asset_id);
+test("Storage[motoko].commit_upload(): should turn the upload into an asset", async function (t) {
+  const { Ok: asset_id, Err: error } = await commitUpload(
+    storage_actors.motoko,
+    upload_id,
+    file_hash
+  );
+  t.equal(error, undefined);
 
-  t.equal(asset.filename, "bots.mp4");
+  const { Ok: asset } = await storage_actors.motoko.get_asset(asset_id);
+  t.equal(asset.file_name, "bots.mp4");
   t.equal(asset.content_type, "video/mp4");
 });
 
-test("FileStorage[motoko].commit_batch(): should err => Invalid Checksum: Chunk Missing", async function (t) {
-  const file_path = "tests/data/bots.mp4";
-
-  const asset_filename = path.basename(file_path);
-  const asset_content_type = mime.getType(file_path);
-
-  const { Ok: asset_id, Err: error } =
-    await file_storage_actors.motoko.commit_batch([],{
-      filename: asset_filename,
-      checksum: checksum,
-      content_encoding: { Identity: null },
-      content_type: asset_content_type,
-    });
-
-  checksum = 0;
-
-  t.equal(error, "Checksum mismatch.");
-});
-
-test("FileStorage[motoko].create_chunk(): should store chunk data of image file to canister", async function (t) {
-
-  const uploadChunk = async ({ chunk, order }) => {
-    return file_storage_actors.motoko.create_chunk(chunk, order);
-  };
-
-  const file_path = "tests/data/poked_3.jpeg";
-
-  const asset_buffer = fs.readFileSync(file_path);
-  const asset_unit8Array = new Uint8Array(asset_buffer);
-
-  const promises = [];
-  const chunkSize = 2000000;
-
-  for (
-    let start = 0, index = 0;
-    start < asset_unit8Array.length;
-    start += chunkSize, index++
-  ) {
-    const chunk = asset_unit8Array.slice(start, start + chunkSize);
-
-    checksum = updateChecksum(chunk, checksum);
-
-    promises.push(
-      uploadChunk({
-        chunk,
-        order: index,
-      })
-    );
-  }
-
-  chunk_ids = await Promise.all(promises);
-
-  const hasChunkIds = chunk_ids.length > 2;
+test("Storage[motoko].commit_upload(): should err on an upload without chunks", async function (t) {
+  const { Ok: id } = await storage_actors.motoko.create_upload({
+    file_name: "bots.mp4",
+    content_type: "video/mp4",
+    total_size: 10,
+    content_encoding: { Identity: null },
+  });
+
+  const { Err: error } = await commitUpload(
+    storage_actors.motoko,
+    id,
+    file_hash
+  );
 
-  t.equal(hasChunkIds, true);
+  t.deepEqual(error, { NoChunks: null });
 });
 
-test("FileStorage[motoko].commit_batch(): should start formation of asset to be stored", async function (t) {
-  const file_path = "tests/data/poked_3.jpeg";
-
+test("Storage[motoko].commit_upload(): should store an image", async function (t) {
+  const file_path = "tests/files/picture.png";
+  const bytes = new Uint8Array(fs.readFileSync(file_path));
   const asset_filename = path.basename(file_path);
   const asset_content_type = mime.getType(file_path);
 
-  const { Ok: asset_id } = await file_storage_actors.motoko.commit_batch(
-    chunk_ids,
-    {
-      filename: asset_filename,
-      checksum: checksum,
-      content_encoding: { Identity: null },
-      content_type: asset_content_type,
-    }
+  const { upload_id } = await uploadFile(storage_actors.motoko, {
+    file_name: asset_filename,
+    content_type: asset_content_type,
+    bytes,
+  });
+  const { Ok: asset_id, Err: error } = await commitUpload(
+    storage_actors.motoko,
+    upload_id,
+    sha256(bytes)
   );
+  t.equal(error, undefined);
 
-  checksum = 0;
-
-  const { Ok: asset } = await file_storage_actors.motoko.get(asset_id);
-
-  t.equal(asset.filename, "poked_3.jpeg");
-  t.equal(asset.content_type, "image/jpeg");
+  const { Ok: asset } = await storage_actors.motoko.get_asset(asset_id);
+  t.equal(asset.file_name, "picture.png");
+  t.equal(asset.content_type, "image/png");
 });
 
-test("FileStorage[motoko].assets_list(): should return all assets without file content data since it would be too large", async function (t) {
-  const asset_list = await file_storage_actors.motoko.assets_list();
-
-  const hasAssets = asset_list.length > 1;
+test("Storage[motoko].list_assets(): should list the assets without their content", async function (t) {
+  const { assets } = await storage_actors.motoko.list_assets(
+    {
+      name_contains: [],
+      content_type_prefix: [],
+      owner: [motoko_identity.getPrincipal()],
+      created_after: [],
+      created_before: [],
+    },
+    [],
+    100
+  );
 
-  t.equal(hasAssets, true);
+  t.equal(assets.length > 1, true);
 });
 
-test("FileStorage[motoko].delete_asset(): should delete an asset", async function (t) {
-  // Upload an asset
-  const file_path = "tests/data/poked_1.jpeg";
-  const asset_buffer = fs.readFileSync(file_path);
-  const asset_unit8Array = new Uint8Array(asset_buffer);
-  const asset_filename = path.basename(file_path);
-  const asset_content_type = mime.getType(file_path);
-
-  checksum = updateChecksum(asset_buffer, checksum);
-
-  const chunk_id = await file_storage_actors.motoko.create_chunk(
-    asset_unit8Array,
-    0
-  );
-  const { Ok: asset_id } = await file_storage_actors.motoko.commit_batch(
-    [chunk_id],
-    {
-      filename: asset_filename,
-      checksum: checksum,
-      content_encoding: { Identity: null },
-      content_type: asset_content_type,
-    }
+test("Storage[motoko].delete_asset(): should delete an asset", async function (t) {
+  const bytes = new Uint8Array(crypto.randomBytes(1000));
+  const { upload_id } = await uploadFile(storage_actors.motoko, {
+    file_name: "poked_1.jpeg",
+    content_type: "image/jpeg",
+    bytes,
+  });
+  const { Ok: asset_id } = await commitUpload(
+    storage_actors.motoko,
+    upload_id,
+    sha256(bytes)
   );
 
-  // Delete the asset
-  const { Ok: delete_result } = await file_storage_actors.motoko.delete_asset(
-    asset_id
-  );
-  t.equal(delete_result, "Success");
+  const { Err: error } = await storage_actors.motoko.delete_asset(asset_id);
+  t.equal(error, undefined);
 
-  // Check if the asset is no longer in the assets list
-  const asset_list = await file_storage_actors.motoko.assets_list();
-  // console.log(asset_list);
-  const deleted_asset = asset_list.find((asset) => asset.id === asset_id);
-  t.equal(deleted_asset, undefined);
+  const { Err: not_found } = await storage_actors.motoko.get_asset(asset_id);
+  t.deepEqual(not_found, { AssetNotFound: null });
 });
 
-// test("FileStorage[motoko].start_clear_expired_chunks(): should start clearing chunks cron job", async function (t) {
-//   const timer_id =
-//     await file_storage_actors.motoko.start_clear_expired_chunks();
-
-//   t.equal(timer_id, 1n);
-// });
-
-test("FileStorage[motoko].is_full(): should return false when memory usage is below threshold", async function (t) {
-  const response = await file_storage_actors.motoko.is_full();
+test("Storage[motoko].is_full(): should be rejected since it is for admins only", async function (t) {
+  let rejected = false;
+  try {
+    await storage_actors.motoko.is_full();
+  } catch (error) {
+    rejected = true;
+  }
 
-  t.equal(response, false);
+  t.equal(rejected, true);
 });
@@ -3,7 +3,7 @@ const { Ed25519KeyIdentity } = require("@dfinity/identity");
 const fs = require("fs");
 const path = require("path");
 const mime = require("mime");
-const { updateChecksum } = require("./utils.cjs");
+const { sha256 } = require("./utils.cjs");
 
 // Actor Interface
 const {
@@ -22,8 +22,9 @@ const { getActor } = require("./actor.cjs");
 
 let storage_actors = {};
 
+let upload_id;
 let chunk_ids = [];
-let checksum = 0;
+let file_hash;
 
 test("Setup Actors", async function (t) {
   console.log("=========== File Storage ===========");
@@ -86,7 +87,7 @@ test("Setup Actors", async function (t) {
 
 //   const ids_sorted = chunk_ids.sort((a, b) => (a < b ? -1 : a > b ? 1 : 0));
 
-//   let id = await storage_actors.identityA.commit_batch({
+//   const { Ok: id, Err: error } = await storage_actors.identityA.commit_batch({
 //     content_type: asset_content_type,
 //     file_name: asset_filename,
 //     chunk_ids: ids_sorted,
@@ -102,8 +103,14 @@ test("Setup Actors", async function (t) {
 // });
 
 test("Upload picture", async function (t) {
-  const uploadChunk = async ({ content, order }) => {
-    return storage_actors.identityA.upload_chunk({ content, order });
+  const uploadChunk = async ({ content, index }) => {
+    const { Ok: id } = await storage_actors.identityA.upload_chunk({
+      upload_id,
+      index,
+      content,
+      sha256: sha256(content),
+    });
+    return id;
   };
   let file_path = "tests/files/video2.mp4";
   const asset_buffer = fs.readFileSync(file_path);
@@ -111,6 +118,15 @@ test("Upload picture", async function (t) {
   const promises = [];
   const chunkSize = 2000000;
 
+  const { Ok: id, Err: error } = await storage_actors.identityA.create_upload({
+    file_name: path.basename(file_path),
+    content_type: mime.getType(file_path),
+    total_size: asset_unit8Array.length,
+    content_encoding: { Identity: null },
+  });
+  t.equal(error, undefined);
+  upload_id = id;
+
   for (
     let start = 0, index = 0;
     start < asset_unit8Array.length;
@@ -118,47 +134,46 @@ test("Upload picture", async function (t) {
   ) {
     const chunk = asset_unit8Array.slice(start, start + chunkSize);
 
-    checksum = updateChecksum(chunk, checksum);
-
-    console.log("checksum: ", checksum);
-
     promises.push(
       uploadChunk({
         content: chunk,
-        order: index,
+        index,
       })
     );
   }
   chunk_ids = await Promise.all(promises);
+  file_hash = sha256(asset_unit8Array);
 
   let response = await storage_actors.identityA.chunk_availability_check(
     chunk_ids
   );
   t.equal(response, true);
+
+  const { Ok: upload } = await storage_actors.identityA.get_upload(upload_id);
+  t.equal(upload.chunks.length, chunk_ids.length);
+  t.equal(upload.received_size, upload.total_size);
 });
 
 test("Should start formation of picture", async function (t) {
-  const file_path = "test/files/video2.mp4";
+  const file_path = "tests/files/video2.mp4";
   const asset_filename = path.basename(file_path);
   const asset_content_type = mime.getType(file_path);
 
-  console.log("checksum: ", checksum);
-
-  const ids_sorted = chunk_ids.sort((a, b) => (a < b ? -1 : a > b ? 1 : 0));
-
-  let id = await storage_actors.identityA.commit_batch({
-    content_type: asset_content_type,
-    file_name: asset_filename,
-    chunk_ids: ids_sorted,
-    checksum: checksum,
-    content_encoding: { Identity: null },
+  const { Ok: id, Err: error } = await storage_actors.identityA.commit_upload({
+    upload_id,
+    sha256: file_hash,
+    visibility: [],
+    description: [],
+    cache_control: [],
+    compress: [],
+    decode: [],
+    disposition: [],
   });
 
+  t.equal(error, undefined);
   console.log("id: ", id);
 
-  checksum = 0;
-
-  const asset = await storage_actors.identityA.get_asset(id);
+  const { Ok: asset } = await storage_actors.identityA.get_asset(id);
   t.equal(asset.file_name, asset_filename);
   t.equal(asset.content_type, asset_content_type);
   console.log(asset);